@@ -1,13 +1,42 @@
-use clap::{load_yaml, value_t_or_exit, App};
+use clap::{load_yaml, value_t_or_exit, App, ArgMatches, ErrorKind};
+use log::LevelFilter;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use crate::color::ChromaKey;
+use crate::compose::Layer;
+use crate::config::Job;
+use crate::image_data::Pixel;
+use crate::live::LiveFormat;
+use crate::logging::LogFormat;
+use crate::motion::MotionOptions;
+use crate::net::{self, IpVersion, NetOptions};
+use crate::proxy::Proxy;
+use crate::source::{self, Source};
+use crate::text::TextOptions;
 
 #[derive(Debug, Clone)]
 pub struct CliOptions {
-    pub file: String,
-    pub url: String,
-    pub offset: (u32, u32),
-    pub similarity: u32,
-    pub shuffle: bool,
-    pub time_factor: u32,
+    /// Job described by the arguments, none if a configuration file is used
+    pub job: Option<Job>,
+    /// Configuration file describing the jobs to run
+    pub config: Option<String>,
+    /// Format of the file if it is flooded as a live stream
+    pub live: Option<LiveFormat>,
+    /// Address of the HTTP control API
+    pub control: Option<SocketAddr>,
+    /// Reloads sources when their files change or on SIGHUP
+    pub watch: bool,
+    /// Interval of the status lines, none if they are disabled
+    pub stats: Option<Duration>,
+    /// Interval of the read-backs measuring grief, none if they are disabled
+    pub read_back: Option<Duration>,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    /// Bytes per second sent over all connections
+    pub max_bandwidth: Option<u64>,
+    /// Pixels per second sent over all connections
+    pub max_pps: Option<u64>,
+    pub net: NetOptions,
 }
 
 pub fn get_options() -> CliOptions {
@@ -15,7 +44,133 @@ pub fn get_options() -> CliOptions {
     let matches = App::from_yaml(yaml).get_matches();
 
     CliOptions {
-        file: matches.value_of("file").unwrap().into(),
+        job: if matches.is_present("config") {
+            None
+        } else {
+            Some(get_job(&matches))
+        },
+        config: matches.value_of("config").map(Into::into),
+        live: matches.value_of("live").map(|format| {
+            format.parse().unwrap_or_else(|err: String| {
+                clap::Error::with_description(&err, ErrorKind::InvalidValue).exit()
+            })
+        }),
+        control: if matches.is_present("control") {
+            Some(value_t_or_exit!(matches, "control", SocketAddr))
+        } else {
+            None
+        },
+        watch: matches.is_present("watch"),
+        stats: if matches.is_present("stats") {
+            Some(seconds_or_exit(&matches, "stats_interval").unwrap_or(Duration::from_secs(1)))
+        } else {
+            None
+        },
+        read_back: seconds_or_exit(&matches, "read_back"),
+        log_level: match (
+            matches.occurrences_of("verbose"),
+            matches.occurrences_of("quiet"),
+        ) {
+            (0, 0) => LevelFilter::Info,
+            (1, _) => LevelFilter::Debug,
+            (_, 0) => LevelFilter::Trace,
+            (_, 1) => LevelFilter::Warn,
+            _ => LevelFilter::Error,
+        },
+        log_format: if matches.is_present("log_format") {
+            value_t_or_exit!(matches, "log_format", LogFormat)
+        } else {
+            LogFormat::Text
+        },
+        max_bandwidth: rate_or_exit(&matches, "max_bandwidth"),
+        max_pps: rate_or_exit(&matches, "max_pps"),
+        net: NetOptions {
+            bind: matches
+                .values_of("bind")
+                .into_iter()
+                .flatten()
+                .flat_map(|bind| {
+                    net::parse_bind(bind).unwrap_or_else(|err| {
+                        clap::Error::with_description(&err, ErrorKind::InvalidValue).exit()
+                    })
+                })
+                .collect(),
+            ip_version: if matches.is_present("ipv4") {
+                IpVersion::V4
+            } else if matches.is_present("ipv6") {
+                IpVersion::V6
+            } else {
+                IpVersion::Any
+            },
+            proxy: if matches.is_present("proxy") {
+                Some(value_t_or_exit!(matches, "proxy", Proxy))
+            } else {
+                None
+            },
+            tls_ca: matches.value_of("tls_ca").map(PathBuf::from),
+            tls_insecure: matches.is_present("tls_insecure"),
+            udp_packet_size: matches.value_of("udp_packet_size").map(|size| {
+                size.parse()
+                    .ok()
+                    .filter(|size| (1..=net::UDP_PAYLOAD_MAX).contains(size))
+                    .unwrap_or_else(|| {
+                        clap::Error::with_description(
+                            &format!(
+                                "Invalid datagram size '{}', expected 1 to {} bytes",
+                                size,
+                                net::UDP_PAYLOAD_MAX
+                            ),
+                            ErrorKind::InvalidValue,
+                        )
+                        .exit()
+                    })
+            }),
+            udp_binary: matches.is_present("udp_binary"),
+            udp_packet_rate: rate_or_exit(&matches, "udp_packet_rate"),
+        },
+    }
+}
+
+fn get_job(matches: &ArgMatches) -> Job {
+    let source = if let Some(source) = matches.value_of("source") {
+        // Paths belong to --file, only layers fall back to them
+        if !["text:", "gen:", "playlist:"]
+            .iter()
+            .any(|prefix| source.starts_with(prefix))
+        {
+            clap::Error::with_description(
+                &format!(
+                    "Unknown source '{}', expected text:<TEXT>, gen:<NAME> or playlist:<FILE>",
+                    source
+                ),
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        }
+        get_source(matches, source)
+    } else if let Some(path) = matches.value_of("frames_dir") {
+        Source::FramesDir {
+            path: path.into(),
+            fps: if matches.is_present("fps") {
+                value_t_or_exit!(matches, "fps", f32)
+            } else {
+                10.0
+            },
+        }
+    } else if let Some(layers) = matches.values_of("layer") {
+        Source::Layers(layers.map(|layer| get_layer(matches, layer)).collect())
+    } else if let Some(path) = matches.value_of("playlist") {
+        Source::Playlist(path.into())
+    } else {
+        let file = matches.value_of("file").unwrap();
+        if source::is_still(file) && !matches.is_present("live") {
+            Source::Still(file.into())
+        } else {
+            Source::Gif(file.into())
+        }
+    };
+
+    Job {
         url: matches.value_of("url").unwrap().into(),
         offset: (
             if matches.is_present("offset_x") {
@@ -29,6 +184,41 @@ pub fn get_options() -> CliOptions {
                 0
             },
         ),
+        key: matches.value_of("key_color").map(|color| ChromaKey {
+            color: color.parse().unwrap_or_else(|err: String| {
+                clap::Error::with_description(&err, ErrorKind::InvalidValue).exit()
+            }),
+            tolerance: if matches.is_present("key_tolerance") {
+                value_t_or_exit!(matches, "key_tolerance", u32)
+            } else {
+                0
+            },
+        }),
+        transforms: matches
+            .values_of("transform")
+            .into_iter()
+            .flatten()
+            .map(|transform| {
+                transform.parse().unwrap_or_else(|err: String| {
+                    clap::Error::with_description(&err, ErrorKind::InvalidValue).exit()
+                })
+            })
+            .collect(),
+        filters: matches
+            .values_of("filter")
+            .into_iter()
+            .flatten()
+            .map(|filter| {
+                filter.parse().unwrap_or_else(|err: String| {
+                    clap::Error::with_description(&err, ErrorKind::InvalidValue).exit()
+                })
+            })
+            .collect(),
+        scale: if matches.is_present("scale") {
+            value_t_or_exit!(matches, "scale", u32).max(1)
+        } else {
+            1
+        },
         similarity: if matches.is_present("similarity") {
             value_t_or_exit!(matches, "similarity", u32)
         } else {
@@ -46,7 +236,208 @@ pub fn get_options() -> CliOptions {
         time_factor: if matches.is_present("time_factor") {
             value_t_or_exit!(matches, "time_factor", u32)
         } else {
-            10
+            source.default_time_factor()
         },
+        source,
+        motion: matches.value_of("motion").map(|motion| {
+            let mut options = MotionOptions::new(motion.parse().unwrap_or_else(|err: String| {
+                clap::Error::with_description(&err, ErrorKind::InvalidValue).exit()
+            }));
+            if matches.is_present("motion_speed") {
+                options.speed = value_t_or_exit!(matches, "motion_speed", f32);
+            }
+            if let Some(color) = color_or_exit(matches, "motion_background") {
+                options.background = color;
+            }
+            options
+        }),
+        canvas: size_or_exit(matches, "canvas"),
+        tile: matches.value_of("tile").map(|tile| {
+            tile.parse().unwrap_or_else(|err: String| {
+                clap::Error::with_description(&err, ErrorKind::InvalidValue).exit()
+            })
+        }),
+    }
+}
+
+fn get_source(matches: &ArgMatches, source: &str) -> Source {
+    let mut source: Source = source.parse().unwrap_or_else(|err: String| {
+        clap::Error::with_description(&err, ErrorKind::InvalidValue).exit()
+    });
+    match &mut source {
+        Source::Text(options) => apply_text_options(matches, options),
+        Source::Generator(options) => {
+            if let Some(size) = size_or_exit(matches, "gen_size") {
+                options.size = size;
+            }
+            if matches.is_present("gen_frames") {
+                options.frames = value_t_or_exit!(matches, "gen_frames", u32);
+            }
+            if let Some(color) = color_or_exit(matches, "gen_color") {
+                options.color = color;
+            }
+            if let Some(color) = color_or_exit(matches, "gen_color2") {
+                options.color2 = color;
+            }
+            if matches.is_present("seed") {
+                options.seed = Some(value_t_or_exit!(matches, "seed", u64));
+            }
+            apply_text_options(matches, &mut options.text);
+        }
+        Source::FramesDir { fps, .. } => {
+            if matches.is_present("fps") {
+                *fps = value_t_or_exit!(matches, "fps", f32);
+            }
+        }
+        Source::Gif(_) | Source::Still(_) | Source::Layers(_) | Source::Playlist(_) => {}
+    }
+    source
+}
+
+/// Parses `<SOURCE>[@X,Y]`, sources of layers are parsed like `--source`
+fn get_layer(matches: &ArgMatches, layer: &str) -> Layer {
+    let offset = layer.rsplit_once('@').and_then(|(source, offset)| {
+        let (x, y) = offset.split_once(',')?;
+        Some((source, (x.parse().ok()?, y.parse().ok()?)))
+    });
+    let (source, offset) = offset.unwrap_or((layer, (0, 0)));
+    Layer {
+        source: get_source(matches, source),
+        offset,
+    }
+}
+
+fn apply_text_options(matches: &ArgMatches, options: &mut TextOptions) {
+    if let Some(font) = matches.value_of("font") {
+        options.font = Some(font.into());
+    }
+    if matches.is_present("font_size") {
+        options.font_size = value_t_or_exit!(matches, "font_size", f32);
+    }
+    if let Some(color) = color_or_exit(matches, "text_color") {
+        options.color = color;
+    }
+    if let Some(color) = color_or_exit(matches, "text_background") {
+        options.background = Some(color);
+    }
+    if matches.is_present("text_scale") {
+        options.scale = value_t_or_exit!(matches, "text_scale", u32);
+    }
+    if matches.is_present("marquee") {
+        options.marquee = Some(value_t_or_exit!(matches, "marquee", u32));
+    }
+    if matches.is_present("marquee_speed") {
+        options.marquee_speed = value_t_or_exit!(matches, "marquee_speed", u32);
+    }
+}
+
+fn size_or_exit(matches: &ArgMatches, name: &str) -> Option<(u32, u32)> {
+    matches.value_of(name).map(|size| {
+        parse_size(size).unwrap_or_else(|| {
+            clap::Error::with_description(
+                &format!("Invalid size '{}', expected the format WxH", size),
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        })
+    })
+}
+
+pub fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+fn seconds_or_exit(matches: &ArgMatches, name: &str) -> Option<Duration> {
+    matches.value_of(name).map(|seconds| {
+        seconds
+            .parse()
+            .ok()
+            .filter(|&seconds: &f32| seconds > 0.0 && seconds.is_finite())
+            .map(Duration::from_secs_f32)
+            .unwrap_or_else(|| {
+                clap::Error::with_description(
+                    &format!("Invalid duration '{}', expected positive seconds", seconds),
+                    ErrorKind::InvalidValue,
+                )
+                .exit()
+            })
+    })
+}
+
+/// Parses a positive rate with an optional `k`, `M` or `G` suffix
+pub fn parse_rate(rate: &str) -> Option<u64> {
+    let (number, factor) = match rate.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&rate[..i], 1e3),
+        Some((i, 'M')) => (&rate[..i], 1e6),
+        Some((i, 'G')) => (&rate[..i], 1e9),
+        _ => (rate, 1.0),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .map(|number| (number * factor).round())
+        .filter(|&rate| rate >= 1.0 && rate.is_finite())
+        .map(|rate| rate as u64)
+}
+
+fn rate_or_exit(matches: &ArgMatches, name: &str) -> Option<u64> {
+    matches.value_of(name).map(|rate| {
+        parse_rate(rate).unwrap_or_else(|| {
+            clap::Error::with_description(
+                &format!(
+                    "Invalid rate '{}', expected a number like 800, 500k or 10M",
+                    rate
+                ),
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        })
+    })
+}
+
+fn color_or_exit(matches: &ArgMatches, name: &str) -> Option<(u8, u8, u8)> {
+    matches.value_of(name).map(|hex| {
+        Pixel::hex_to_rgb(hex).unwrap_or_else(|| {
+            clap::Error::with_description(
+                &format!("Invalid colour '{}', expected the format rrggbb", hex),
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_without_suffix() {
+        assert_eq!(parse_rate("800"), Some(800));
+        assert_eq!(parse_rate("1"), Some(1));
+        assert_eq!(parse_rate("2.6"), Some(3));
+    }
+
+    #[test]
+    fn rate_with_suffix() {
+        assert_eq!(parse_rate("500k"), Some(500_000));
+        assert_eq!(parse_rate("500K"), Some(500_000));
+        assert_eq!(parse_rate("1.5M"), Some(1_500_000));
+        assert_eq!(parse_rate("10M"), Some(10_000_000));
+        assert_eq!(parse_rate("2G"), Some(2_000_000_000));
+    }
+
+    #[test]
+    fn invalid_rate() {
+        assert_eq!(parse_rate(""), None);
+        assert_eq!(parse_rate("k"), None);
+        assert_eq!(parse_rate("0"), None);
+        assert_eq!(parse_rate("0.4"), None);
+        assert_eq!(parse_rate("-5k"), None);
+        assert_eq!(parse_rate("10m"), None);
+        assert_eq!(parse_rate("10 M"), None);
+        assert_eq!(parse_rate("inf"), None);
+        assert_eq!(parse_rate("NaN"), None);
     }
 }
@@ -0,0 +1,404 @@
+use log::info;
+use std::{collections::HashMap, error::Error, fs, str::FromStr};
+
+use crate::image_data::{Frame, Pixel};
+
+/// Colour change applied to every pixel of an animation
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColorFilter {
+    /// Multiplies all channels, 1 keeps the colour
+    Brightness(f32),
+    /// Scales the distance from mid grey, 1 keeps the colour
+    Contrast(f32),
+    /// Gamma correction, values above 1 brighten the mid tones
+    Gamma(f32),
+    /// Scales the distance from the grey of the same brightness, 0 is grayscale
+    Saturation(f32),
+    /// Rotates the hue by the given degrees
+    Hue(f32),
+    Grayscale,
+    Invert,
+    /// Replaces each colour with the most similar one of the palette
+    Palette(Vec<(u8, u8, u8)>),
+}
+
+impl FromStr for ColorFilter {
+    type Err = String;
+
+    /// Parses `brightness:F`, `contrast:F`, `gamma:F`, `saturation:F`,
+    /// `hue:DEGREES`, `grayscale`, `invert` or `palette:FILE`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = s.split_once(':').unwrap_or((s, ""));
+        let number = || arg.parse::<f32>().ok().filter(|value| value.is_finite());
+        let filter = match name {
+            "brightness" => number().map(ColorFilter::Brightness),
+            "contrast" => number().map(ColorFilter::Contrast),
+            "gamma" => number()
+                .filter(|&gamma| gamma > 0.0)
+                .map(ColorFilter::Gamma),
+            "saturation" => number().map(ColorFilter::Saturation),
+            "hue" => number().map(ColorFilter::Hue),
+            "grayscale" if arg.is_empty() => Some(ColorFilter::Grayscale),
+            "invert" if arg.is_empty() => Some(ColorFilter::Invert),
+            "palette" => {
+                return load_palette(arg)
+                    .map_err(|err| format!("Can't load the palette {}: {}", arg, err))
+            }
+            _ => None,
+        };
+        filter.ok_or_else(|| {
+            format!(
+                "Invalid filter '{}', expected brightness:F, contrast:F, gamma:F, saturation:F, hue:DEGREES, grayscale, invert or palette:FILE",
+                s
+            )
+        })
+    }
+}
+
+/// Reads a palette with one colour per line, either as `rrggbb` or as the
+/// decimal `R G B` of GIMP palettes
+fn load_palette(path: &str) -> Result<ColorFilter, Box<dyn Error>> {
+    let palette: Vec<_> = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| {
+            let mut channels = line.split_whitespace().map(|value| value.parse().ok());
+            match (channels.next(), channels.next(), channels.next()) {
+                (Some(Some(r)), Some(Some(g)), Some(Some(b))) => Some((r, g, b)),
+                _ => Pixel::hex_to_rgb(line.split_whitespace().next()?),
+            }
+        })
+        .collect();
+    if palette.is_empty() {
+        return Err("The file contains no colours".into());
+    }
+    Ok(ColorFilter::Palette(palette))
+}
+
+/// Colour that is turned transparent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyColor {
+    Rgb(u8, u8, u8),
+    /// Most common colour on the border of the first frame
+    Auto,
+}
+
+impl FromStr for KeyColor {
+    type Err = String;
+
+    /// Parses `rrggbb` or `auto`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(KeyColor::Auto);
+        }
+        Pixel::hex_to_rgb(s)
+            .map(|(r, g, b)| KeyColor::Rgb(r, g, b))
+            .ok_or_else(|| format!("Invalid key colour '{}', expected rrggbb or auto", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChromaKey {
+    pub color: KeyColor,
+    /// Largest similarity value of pixels that are keyed out
+    pub tolerance: u32,
+}
+
+/// Turns pixels similar to the key colour transparent, so they are never drawn
+///
+/// Only the static background is keyed out. Where any frame shows something
+/// else, the key colour is still drawn, since it erases that content again.
+pub fn key_out(frames: &mut [Frame], key: &ChromaKey) {
+    let color = match key.color {
+        KeyColor::Rgb(r, g, b) => Pixel::Rgb(r, g, b),
+        KeyColor::Auto => match frames.first().and_then(border_color) {
+            Some(color) => color,
+            None => return,
+        },
+    };
+    if let Pixel::Rgb(r, g, b) = color {
+        info!("🔑 Keying out {:02x}{:02x}{:02x}", r, g, b);
+    }
+    let keyed = |pixel: Pixel| pixel.similarity(color) <= key.tolerance;
+
+    // Positions that show anything but the key colour in some frame
+    let start = frames.iter().fold((u32::MAX, u32::MAX), |start, frame| {
+        (start.0.min(frame.offset().0), start.1.min(frame.offset().1))
+    });
+    let end = frames.iter().fold((0, 0), |end, frame| {
+        (end.0.max(frame.end().0), end.1.max(frame.end().1))
+    });
+    let width = end.0.saturating_sub(start.0);
+    let index = |(x, y): (u32, u32)| ((x - start.0) + width * (y - start.1)) as usize;
+    let mut content = vec![false; width as usize * end.1.saturating_sub(start.1) as usize];
+    for frame in frames.iter() {
+        let (x0, y0) = frame.offset();
+        let (x1, y1) = frame.end();
+        for (x, y) in (y0..y1).flat_map(|y| (x0..x1).map(move |x| (x, y))) {
+            let pixel = frame.get(x, y);
+            if pixel != Pixel::Empty && !keyed(pixel) {
+                content[index((x, y))] = true;
+            }
+        }
+    }
+
+    for frame in frames {
+        frame.map_positioned_pixels(|position, pixel| {
+            if keyed(pixel) && !content[index(position)] {
+                Pixel::Empty
+            } else {
+                pixel
+            }
+        });
+    }
+}
+
+/// Most common visible colour on the edges of the frame
+fn border_color(frame: &Frame) -> Option<Pixel> {
+    let (x0, y0) = frame.offset();
+    let (x1, y1) = frame.end();
+    let mut counts = HashMap::new();
+    let horizontal = (x0..x1).flat_map(|x| [(x, y0), (x, y1.saturating_sub(1))]);
+    let vertical = (y0..y1).flat_map(|y| [(x0, y), (x1.saturating_sub(1), y)]);
+    for (x, y) in horizontal.chain(vertical) {
+        if let Pixel::Rgb(r, g, b) = frame.get(x, y) {
+            *counts.entry((r, g, b)).or_insert(0) += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(_, count)| count)
+        .map(|((r, g, b), _)| Pixel::Rgb(r, g, b))
+}
+
+/// Applies the filters in order to all visible pixels
+pub fn apply(frames: &mut [Frame], filters: &[ColorFilter]) {
+    for frame in frames {
+        frame.map_pixels(|pixel| match pixel {
+            Pixel::Rgb(r, g, b) => {
+                let rgb = filters
+                    .iter()
+                    .fold((r, g, b), |rgb, filter| filter.apply(rgb));
+                Pixel::Rgb(rgb.0, rgb.1, rgb.2)
+            }
+            Pixel::Empty => Pixel::Empty,
+        });
+    }
+}
+
+impl ColorFilter {
+    fn apply(&self, (r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+        let (r, g, b) = (r as f32, g as f32, b as f32);
+        let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+        let each = |f: &dyn Fn(f32) -> f32| (f(r), f(g), f(b));
+        let rgb = match self {
+            ColorFilter::Brightness(factor) => each(&|c| c * factor),
+            ColorFilter::Contrast(factor) => each(&|c| (c - 128.0) * factor + 128.0),
+            ColorFilter::Gamma(gamma) => each(&|c| 255.0 * (c / 255.0).powf(1.0 / gamma)),
+            ColorFilter::Saturation(factor) => each(&|c| luma + (c - luma) * factor),
+            ColorFilter::Grayscale => (luma, luma, luma),
+            ColorFilter::Invert => each(&|c| 255.0 - c),
+            ColorFilter::Hue(degrees) => {
+                // Rotation around the grey axis as in the CSS hue-rotate filter
+                let (sin, cos) = degrees.to_radians().sin_cos();
+                (
+                    r * (0.213 + cos * 0.787 - sin * 0.213)
+                        + g * (0.715 - cos * 0.715 - sin * 0.715)
+                        + b * (0.072 - cos * 0.072 + sin * 0.928),
+                    r * (0.213 - cos * 0.213 + sin * 0.143)
+                        + g * (0.715 + cos * 0.285 + sin * 0.140)
+                        + b * (0.072 - cos * 0.072 - sin * 0.283),
+                    r * (0.213 - cos * 0.213 - sin * 0.787)
+                        + g * (0.715 - cos * 0.715 + sin * 0.715)
+                        + b * (0.072 + cos * 0.928 + sin * 0.072),
+                )
+            }
+            ColorFilter::Palette(palette) => {
+                let pixel = Pixel::Rgb(r as u8, g as u8, b as u8);
+                let nearest = palette
+                    .iter()
+                    .min_by_key(|&&(r, g, b)| pixel.similarity(Pixel::Rgb(r, g, b)))
+                    .unwrap();
+                return *nearest;
+            }
+        };
+        let channel = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+        (channel(rgb.0), channel(rgb.1), channel(rgb.2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Writes the palette to a file that is unique to the test
+    fn palette_file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("pixelflut-{}-{}.gpl", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn parse_filter() {
+        assert_eq!("brightness:1.5".parse(), Ok(ColorFilter::Brightness(1.5)));
+        assert_eq!("contrast:0.8".parse(), Ok(ColorFilter::Contrast(0.8)));
+        assert_eq!("gamma:2.2".parse(), Ok(ColorFilter::Gamma(2.2)));
+        assert_eq!("saturation:0".parse(), Ok(ColorFilter::Saturation(0.0)));
+        assert_eq!("hue:-90".parse(), Ok(ColorFilter::Hue(-90.0)));
+        assert_eq!("grayscale".parse(), Ok(ColorFilter::Grayscale));
+        assert_eq!("invert".parse(), Ok(ColorFilter::Invert));
+    }
+
+    #[test]
+    fn parse_invalid_filter() {
+        for filter in &[
+            "",
+            "sepia",
+            "brightness:",
+            "brightness:bright",
+            "brightness:inf",
+            "contrast:NaN",
+            "gamma:0",
+            "hue:90deg",
+            "invert:yes",
+            "palette:",
+        ] {
+            assert!(filter.parse::<ColorFilter>().is_err(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn filter_maths() {
+        let filter = |filter: ColorFilter, rgb| filter.apply(rgb);
+        assert_eq!(
+            filter(ColorFilter::Brightness(1.5), (100, 200, 10)),
+            (150, 255, 15)
+        );
+        assert_eq!(
+            filter(ColorFilter::Contrast(2.0), (100, 200, 128)),
+            (72, 255, 128)
+        );
+        assert_eq!(filter(ColorFilter::Gamma(2.0), (64, 255, 0)), (128, 255, 0));
+        assert_eq!(
+            filter(ColorFilter::Saturation(0.0), (255, 0, 0)),
+            (76, 76, 76)
+        );
+        assert_eq!(
+            filter(ColorFilter::Saturation(2.0), (100, 150, 50)),
+            (76, 176, 0)
+        );
+        assert_eq!(filter(ColorFilter::Grayscale, (0, 255, 0)), (150, 150, 150));
+        assert_eq!(filter(ColorFilter::Invert, (0, 128, 255)), (255, 127, 0));
+        assert_eq!(filter(ColorFilter::Hue(0.0), (10, 200, 30)), (10, 200, 30));
+        assert_eq!(filter(ColorFilter::Hue(120.0), (255, 0, 0)), (0, 113, 0));
+        // Grey has no hue to rotate
+        assert_eq!(
+            filter(ColorFilter::Hue(77.0), (128, 128, 128)),
+            (128, 128, 128)
+        );
+
+        let palette = ColorFilter::Palette(vec![(0, 0, 0), (255, 255, 255), (255, 0, 0)]);
+        assert_eq!(palette.apply((200, 30, 20)), (255, 0, 0));
+        assert_eq!(palette.apply((230, 230, 230)), (255, 255, 255));
+        assert_eq!(palette.apply((20, 10, 10)), (0, 0, 0));
+    }
+
+    #[test]
+    fn apply_filters_in_order() {
+        let pixels = vec![Pixel::Rgb(255, 0, 100), Pixel::Empty];
+        let mut frames = [Frame::new(pixels.clone(), (0, 0), (2, 1), 10)];
+        apply(
+            &mut frames,
+            &[ColorFilter::Invert, ColorFilter::Brightness(0.5)],
+        );
+        assert_eq!(frames[0].get(0, 0), Pixel::Rgb(0, 128, 78));
+        assert_eq!(frames[0].get(1, 0), Pixel::Empty);
+
+        let mut frames = [Frame::new(pixels, (0, 0), (2, 1), 10)];
+        apply(
+            &mut frames,
+            &[ColorFilter::Brightness(0.5), ColorFilter::Invert],
+        );
+        assert_eq!(frames[0].get(0, 0), Pixel::Rgb(127, 255, 205));
+    }
+
+    #[test]
+    fn parse_gimp_palette() {
+        let path = palette_file(
+            "gimp",
+            "GIMP Palette\n\
+             Name: Test\n\
+             Columns: 3\n\
+             #\n\
+             \x20 0   0   0\tBlack\n\
+             255 255 255\tWhite\n\
+             \n\
+             190  38  51\tRed\n",
+        );
+        let filter = format!("palette:{}", path.display()).parse();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            filter,
+            Ok(ColorFilter::Palette(vec![
+                (0, 0, 0),
+                (255, 255, 255),
+                (190, 38, 51)
+            ]))
+        );
+    }
+
+    #[test]
+    fn parse_hex_palette() {
+        let path = palette_file("hex", "ff0000\n#00ff00 green\n  0000ff\n");
+        let filter = format!("palette:{}", path.display()).parse();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            filter,
+            Ok(ColorFilter::Palette(vec![
+                (255, 0, 0),
+                (0, 255, 0),
+                (0, 0, 255)
+            ]))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_palette() {
+        let path = palette_file("empty", "GIMP Palette\nName: Empty\n#\n300 0 0\nfff\n");
+        let filter = format!("palette:{}", path.display()).parse::<ColorFilter>();
+        fs::remove_file(&path).unwrap();
+        assert!(filter.unwrap_err().contains("no colours"));
+
+        let path = palette_file("missing", "");
+        fs::remove_file(&path).unwrap();
+        assert!(format!("palette:{}", path.display())
+            .parse::<ColorFilter>()
+            .is_err());
+    }
+
+    #[test]
+    fn key_out_keeps_erasing_moving_sprites() {
+        let (red, green) = (Pixel::Rgb(255, 0, 0), Pixel::Rgb(0, 255, 0));
+        // A red sprite moves right over a green background, the second GIF
+        // frame only covers the area that changed
+        let mut frames = vec![
+            Frame::new(vec![red, green, green], (0, 0), (3, 1), 10),
+            Frame::new(vec![green, red], (0, 0), (2, 1), 10),
+        ];
+        let key = ChromaKey {
+            color: KeyColor::Rgb(0, 255, 0),
+            tolerance: 0,
+        };
+        key_out(&mut frames, &key);
+        let row = |frame: &Frame| (0..3).map(|x| frame.get(x, 0)).collect::<Vec<_>>();
+        assert_eq!(row(&frames[0]), [red, green, Pixel::Empty]);
+        assert_eq!(row(&frames[1]), [green, red, Pixel::Empty]);
+
+        // The background is drawn again where the sprite was
+        let optimized = crate::image_data::optimize_image(frames, 0);
+        assert_eq!(optimized.frames[1].get(0, 0), green);
+        assert_eq!(optimized.frames[2].get(1, 0), green);
+    }
+}
@@ -0,0 +1,128 @@
+use log::warn;
+use std::collections::BTreeSet;
+
+use crate::image_data::Frame;
+use crate::source::Source;
+
+/// Longest loop of a composition in 10ms, longer common loops are cut at the longest layer
+const MAX_DURATION: u64 = 60000;
+
+/// Source drawn at an offset on top of the layers before it
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub source: Source,
+    pub offset: (u32, u32),
+}
+
+/// Complete images of an animation and the times at which they are shown
+pub struct Timeline {
+    states: Vec<Frame>,
+    starts: Vec<u64>,
+    /// Length of one loop in 10ms, 0 for still layers
+    duration: u64,
+}
+
+impl Timeline {
+    pub fn new(frames: Vec<Frame>, offset: (u32, u32)) -> Self {
+        let mut states: Vec<Frame> = Vec::with_capacity(frames.len());
+        let mut starts = Vec::with_capacity(frames.len());
+        let mut time = 0;
+        for mut frame in frames {
+            frame.translate(offset);
+            starts.push(time);
+            time += frame.delay() as u64;
+            // GIF frames only contain what changed, so draw them over the previous state
+            let state = match states.last() {
+                Some(previous) => previous.combine(&frame, 0),
+                None => frame,
+            };
+            states.push(state);
+        }
+        Timeline {
+            duration: if states.len() > 1 { time } else { 0 },
+            states,
+            starts,
+        }
+    }
+
+    /// All complete images
+    pub fn states(&self) -> &[Frame] {
+        &self.states
+    }
+
+    /// Image shown at the given time
+    pub fn state_at(&self, time: u64) -> &Frame {
+        if self.duration == 0 {
+            return self.states.last().unwrap();
+        }
+        let time = time % self.duration;
+        let index = self.starts.partition_point(|&start| start <= time);
+        &self.states[index.max(1) - 1]
+    }
+}
+
+/// Flattens the frames of several layers into one animation
+///
+/// The first layer is at the bottom, transparent pixels show the layers
+/// below. A new frame starts whenever any layer changes and the result
+/// loops over the common multiple of the layer durations.
+pub fn compose(layers: Vec<(Vec<Frame>, (u32, u32))>) -> Vec<Frame> {
+    let timelines: Vec<Timeline> = layers
+        .into_iter()
+        .map(|(frames, offset)| Timeline::new(frames, offset))
+        .collect();
+
+    let durations = timelines
+        .iter()
+        .map(|timeline| timeline.duration)
+        .filter(|&duration| duration > 0);
+    let longest = durations.clone().max().unwrap_or(0);
+    let common = durations.fold(1, |common, duration| {
+        (common / gcd(common, duration)).saturating_mul(duration)
+    });
+    let total = if common > MAX_DURATION.max(longest) {
+        warn!(
+            "⚠️ Layers only loop after {}s, cutting the loop",
+            common / 100
+        );
+        longest
+    } else if longest == 0 {
+        0
+    } else {
+        common
+    };
+
+    let mut changes = BTreeSet::new();
+    changes.insert(0);
+    for timeline in timelines.iter().filter(|timeline| timeline.duration > 0) {
+        for repeat in (0..total).step_by(timeline.duration as usize) {
+            changes.extend(timeline.starts.iter().map(|start| repeat + start));
+        }
+    }
+    let changes: Vec<u64> = changes
+        .into_iter()
+        .filter(|&time| time < total.max(1))
+        .collect();
+
+    changes
+        .iter()
+        .enumerate()
+        .map(|(i, &time)| {
+            let end = changes.get(i + 1).copied().unwrap_or(total);
+            let mut states = timelines.iter().map(|timeline| timeline.state_at(time));
+            let first = states.next().unwrap().clone();
+            let mut frame = states.fold(first, |below, above| below.combine(above, 0));
+            let delay = if total == 0 { 10 } else { end - time };
+            frame.set_delay(delay.min(u16::MAX as u64) as u16);
+            frame
+        })
+        .collect()
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
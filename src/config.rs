@@ -0,0 +1,249 @@
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{error::Error, fs, path::Path, str::FromStr};
+
+use crate::cli;
+use crate::color::{ChromaKey, ColorFilter};
+use crate::compose::Layer;
+use crate::image_data::Pixel;
+use crate::motion::MotionOptions;
+use crate::source::Source;
+use crate::transform::Transform;
+
+/// A source flooded onto one region of a server's canvas
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub source: Source,
+    pub url: String,
+    pub offset: (u32, u32),
+    /// Colour keyed out before any other changes
+    pub key: Option<ChromaKey>,
+    /// Changes applied to the frames in order, before scaling
+    pub transforms: Vec<Transform>,
+    /// Colour changes applied in order after the transforms
+    pub filters: Vec<ColorFilter>,
+    /// Integer factor by which the frames are scaled up
+    pub scale: u32,
+    pub similarity: u32,
+    pub shuffle: bool,
+    pub time_factor: u32,
+    /// Moves the image over the canvas instead of keeping it at the offset
+    pub motion: Option<MotionOptions>,
+    /// Size of the server canvas, queried from the server if needed and none
+    pub canvas: Option<(u32, u32)>,
+    /// Area filled with copies of the image
+    pub tile: Option<TileArea>,
+}
+
+/// Size of the area filled with copies of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileArea {
+    Size(u32, u32),
+    /// Rest of the canvas to the right of and below the offset
+    Canvas,
+}
+
+impl FromStr for TileArea {
+    type Err = String;
+
+    /// Parses `WxH` or `canvas`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "canvas" {
+            return Ok(TileArea::Canvas);
+        }
+        cli::parse_size(s)
+            .map(|(width, height)| TileArea::Size(width, height))
+            .ok_or_else(|| format!("Invalid tile area '{}', expected WxH or canvas", s))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    jobs: Vec<JobConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct JobConfig {
+    /// Same as the `--source` argument, or a GIF path or URL
+    source: Option<String>,
+    /// Sources stacked from bottom to top instead of a single source
+    #[serde(default)]
+    layers: Vec<LayerConfig>,
+    #[serde(alias = "server")]
+    url: String,
+    #[serde(default)]
+    x: u32,
+    #[serde(default)]
+    y: u32,
+    /// Same as the `--key-color` argument
+    key_color: Option<String>,
+    #[serde(default)]
+    key_tolerance: u32,
+    /// Same as the `--transform` arguments
+    #[serde(default)]
+    transforms: Vec<String>,
+    /// Same as the `--filter` arguments
+    #[serde(default)]
+    filters: Vec<String>,
+    #[serde(default = "default_scale")]
+    scale: u32,
+    #[serde(default)]
+    similarity: u32,
+    #[serde(default = "default_shuffle")]
+    shuffle: bool,
+    time_factor: Option<u32>,
+    /// Same as the `--motion` argument
+    motion: Option<String>,
+    motion_speed: Option<f32>,
+    motion_background: Option<String>,
+    /// Canvas size as `WxH`
+    canvas: Option<String>,
+    /// Same as the `--tile` argument
+    tile: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerConfig {
+    source: String,
+    #[serde(default)]
+    x: u32,
+    #[serde(default)]
+    y: u32,
+}
+
+fn default_scale() -> u32 {
+    1
+}
+
+fn default_shuffle() -> bool {
+    true
+}
+
+/// Loads the jobs of a YAML file or, for any other extension, a TOML file
+///
+/// Relative paths of sources are resolved against the directory of the file.
+pub fn load_jobs(path: &str) -> Result<Vec<Job>, Box<dyn Error>> {
+    let path = Path::new(path);
+    let config: Config = parse(path)?;
+    if config.jobs.is_empty() {
+        return Err("The configuration contains no jobs".into());
+    }
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+    config
+        .jobs
+        .into_iter()
+        .map(|job| {
+            let source: Source = match (job.source, job.layers.is_empty()) {
+                (Some(source), true) => resolve(base, &source).parse()?,
+                (None, false) => Source::Layers(
+                    job.layers
+                        .iter()
+                        .map(|layer| {
+                            Ok(Layer {
+                                source: resolve(base, &layer.source).parse()?,
+                                offset: (layer.x, layer.y),
+                            })
+                        })
+                        .collect::<Result<_, String>>()?,
+                ),
+                _ => {
+                    return Err(
+                        format!("Job for {} needs either a source or layers", job.url).into(),
+                    )
+                }
+            };
+            let motion = match &job.motion {
+                Some(motion) => {
+                    let mut options = MotionOptions::new(motion.parse()?);
+                    if let Some(speed) = job.motion_speed {
+                        options.speed = speed;
+                    }
+                    if let Some(hex) = &job.motion_background {
+                        options.background = Pixel::hex_to_rgb(hex)
+                            .ok_or_else(|| format!("Invalid colour '{}'", hex))?;
+                    }
+                    Some(options)
+                }
+                None => None,
+            };
+            let canvas = match &job.canvas {
+                Some(size) => Some(
+                    cli::parse_size(size)
+                        .ok_or_else(|| format!("Invalid canvas size '{}'", size))?,
+                ),
+                None => None,
+            };
+            if job.motion.is_some() && job.tile.is_some() {
+                return Err(format!("Job for {} can't both move and tile", job.url).into());
+            }
+            let tile = match &job.tile {
+                Some(tile) => Some(tile.parse()?),
+                None => None,
+            };
+            let transforms = job
+                .transforms
+                .iter()
+                .map(|transform| transform.parse())
+                .collect::<Result<_, String>>()?;
+            let key = match &job.key_color {
+                Some(color) => Some(ChromaKey {
+                    color: color.parse()?,
+                    tolerance: job.key_tolerance,
+                }),
+                None => None,
+            };
+            let filters = job
+                .filters
+                .iter()
+                .map(|filter| match filter.strip_prefix("palette:") {
+                    Some(path) => format!("palette:{}", resolve(base, path)).parse(),
+                    None => filter.parse(),
+                })
+                .collect::<Result<_, String>>()?;
+            Ok(Job {
+                url: job.url,
+                offset: (job.x, job.y),
+                key,
+                transforms,
+                filters,
+                scale: job.scale.max(1),
+                similarity: job.similarity,
+                shuffle: job.shuffle,
+                time_factor: job
+                    .time_factor
+                    .unwrap_or_else(|| source.default_time_factor()),
+                source,
+                motion,
+                canvas,
+                tile,
+            })
+        })
+        .collect()
+}
+
+/// Reads a YAML file if it has a `.yml` or `.yaml` extension and TOML otherwise
+pub fn parse<T: DeserializeOwned>(path: &Path) -> Result<T, Box<dyn Error>> {
+    let data = fs::read_to_string(path)?;
+    Ok(match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yml") | Some("yaml") => serde_yaml::from_str(&data)?,
+        _ => toml::from_str(&data)?,
+    })
+}
+
+/// Makes paths in a source relative to the directory of the file it is configured in
+pub fn resolve(base: &Path, source: &str) -> String {
+    if let Some(playlist) = source.strip_prefix("playlist:") {
+        return format!("playlist:{}", resolve(base, playlist));
+    }
+    let is_path = !["text:", "gen:", "http:", "https:"]
+        .iter()
+        .any(|prefix| source.starts_with(prefix));
+    if is_path && source != "-" && Path::new(source).is_relative() {
+        base.join(source).to_string_lossy().into_owned()
+    } else {
+        source.into()
+    }
+}
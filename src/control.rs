@@ -0,0 +1,290 @@
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::info;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+};
+use tokio::{
+    runtime::Builder,
+    sync::{oneshot, watch},
+};
+
+use crate::config::Job;
+use crate::image_data::FlutInstructions;
+use crate::metrics;
+use crate::reload::SourceFiles;
+use crate::source::Source;
+use crate::stats::Stats;
+
+/// Settings of a running job, picked up by the flooding loop at the next frame
+#[derive(Debug, Clone)]
+pub struct Control {
+    pub commands: Arc<FlutInstructions>,
+    pub time_factor: u64,
+    pub paused: bool,
+}
+
+/// Job that can be changed through the control API while it is flooded
+pub struct JobHandle {
+    state: Mutex<(Job, Control)>,
+    sender: watch::Sender<Control>,
+    /// Only one rebuild of the commands runs at a time
+    rebuild: tokio::sync::Mutex<()>,
+    /// Files the commands were built from, watched for changes
+    files: Mutex<Arc<SourceFiles>>,
+    pub stats: Arc<Stats>,
+}
+
+impl JobHandle {
+    pub fn new(job: Job, commands: FlutInstructions) -> (Self, watch::Receiver<Control>) {
+        let control = Control {
+            commands: Arc::new(commands),
+            time_factor: job.time_factor as u64,
+            paused: false,
+        };
+        let (sender, receiver) = watch::channel(control.clone());
+        let handle = JobHandle {
+            files: Mutex::new(Arc::new(SourceFiles::of(&job.source))),
+            state: Mutex::new((job, control)),
+            sender,
+            rebuild: tokio::sync::Mutex::new(()),
+            stats: Arc::new(Stats::default()),
+        };
+        (handle, receiver)
+    }
+
+    fn update<F: FnOnce(&mut Control)>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state.1);
+        let _ = self.sender.send(state.1.clone());
+    }
+
+    /// Regenerates the commands after changing the job, which is kept if that fails
+    async fn rebuild<F: FnOnce(&mut Job)>(&self, f: F) -> Result<(), String> {
+        let _rebuild = self.rebuild.lock().await;
+        let mut job = self.state.lock().unwrap().0.clone();
+        f(&mut job);
+        let (send_done, done) = oneshot::channel();
+        let new_job = job.clone();
+        // Loading may block on downloads, so it runs on its own runtime
+        thread::spawn(move || {
+            // Files changing while loading are seen as changed afterwards
+            let files = SourceFiles::of(&new_job.source);
+            let result = Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|err| err.to_string())
+                .and_then(|rt| crate::prepare(&new_job, &rt).map_err(|err| err.to_string()));
+            let _ = send_done.send(result.map(|commands| (commands, files)));
+        });
+        let (commands, files) = done.await.map_err(|err| err.to_string())??;
+
+        *self.files.lock().unwrap() = Arc::new(files);
+        let mut state = self.state.lock().unwrap();
+        state.0 = job;
+        state.1.commands = Arc::new(commands);
+        let _ = self.sender.send(state.1.clone());
+        Ok(())
+    }
+
+    pub fn job(&self) -> Job {
+        self.state.lock().unwrap().0.clone()
+    }
+
+    pub fn control(&self) -> Control {
+        self.state.lock().unwrap().1.clone()
+    }
+
+    pub fn files(&self) -> Arc<SourceFiles> {
+        self.files.lock().unwrap().clone()
+    }
+
+    /// Loads the source again and swaps in the new commands
+    pub async fn reload(&self) -> Result<(), String> {
+        self.rebuild(|_| {}).await
+    }
+
+    fn status(&self, id: usize) -> JobStatus {
+        let state = self.state.lock().unwrap();
+        let (job, control) = &*state;
+        JobStatus {
+            id,
+            url: job.url.clone(),
+            source: job.source.to_string(),
+            x: job.offset.0,
+            y: job.offset.1,
+            time_factor: control.time_factor,
+            shuffle: job.shuffle,
+            paused: control.paused,
+            frames: control.commands.frames.len(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JobStatus {
+    id: usize,
+    url: String,
+    source: String,
+    x: u32,
+    y: u32,
+    time_factor: u64,
+    shuffle: bool,
+    paused: bool,
+    frames: usize,
+}
+
+/// Serves the control API until the process exits
+///
+/// `GET /status` lists the jobs and `GET /metrics` exports their statistics
+/// for Prometheus. `POST /pause`, `/resume`, `/source` with
+/// the source in the body, `/offset?x=X&y=Y`, `/time-factor?value=N` and
+/// `/shuffle?value=yes|no` change all jobs or only the one given with `job=N`.
+pub async fn serve(addr: SocketAddr, jobs: Arc<Vec<JobHandle>>) -> Result<(), Box<dyn Error>> {
+    let make_service = make_service_fn(move |_| {
+        let jobs = jobs.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let jobs = jobs.clone();
+                async move {
+                    Ok::<_, Infallible>(match handle(request, &jobs).await {
+                        Ok(response) => response,
+                        Err((status, message)) => Response::builder()
+                            .status(status)
+                            .body(Body::from(message + "\n"))
+                            .unwrap(),
+                    })
+                }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!("🎛️ Control API on http://{}", addr);
+    server.await?;
+    Ok(())
+}
+
+type HandlerError = (StatusCode, String);
+
+async fn handle(
+    request: Request<Body>,
+    jobs: &[JobHandle],
+) -> Result<Response<Body>, HandlerError> {
+    let (method, path) = (request.method().clone(), request.uri().path().to_owned());
+    let query: HashMap<String, String> = request
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+    let param = |name: &str| -> Result<&str, HandlerError> {
+        query.get(name).map(String::as_str).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Missing parameter {}", name),
+            )
+        })
+    };
+    let number = |name: &str| -> Result<u32, HandlerError> {
+        param(name)?.parse().map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid number for {}", name),
+            )
+        })
+    };
+    let selected: Vec<(usize, &JobHandle)> = match query.get("job") {
+        Some(id) => {
+            let id: usize = id
+                .parse()
+                .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid job id".to_owned()))?;
+            let job = jobs
+                .get(id)
+                .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No job {}", id)))?;
+            vec![(id, job)]
+        }
+        None => jobs.iter().enumerate().collect(),
+    };
+
+    match (&method, path.as_str()) {
+        (&Method::GET, "/status") => {}
+        (&Method::GET, "/metrics") => {
+            return Ok(Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(Body::from(metrics::render(jobs)))
+                .unwrap())
+        }
+        (&Method::POST, "/pause") => {
+            for (_, job) in &selected {
+                job.update(|control| control.paused = true);
+            }
+        }
+        (&Method::POST, "/resume") => {
+            for (_, job) in &selected {
+                job.update(|control| control.paused = false);
+            }
+        }
+        (&Method::POST, "/time-factor") => {
+            let time_factor = number("value")? as u64;
+            for (_, job) in &selected {
+                job.update(|control| control.time_factor = time_factor);
+            }
+        }
+        (&Method::POST, "/shuffle") => {
+            let shuffle = match param("value")? {
+                "yes" => true,
+                "no" => false,
+                _ => return Err((StatusCode::BAD_REQUEST, "Expected yes or no".to_owned())),
+            };
+            for (_, job) in &selected {
+                job.rebuild(|job| job.shuffle = shuffle)
+                    .await
+                    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+            }
+        }
+        (&Method::POST, "/offset") => {
+            let offset = (number("x")?, number("y")?);
+            for (_, job) in &selected {
+                job.rebuild(|job| job.offset = offset)
+                    .await
+                    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+            }
+        }
+        (&Method::POST, "/source") => {
+            let body = hyper::body::to_bytes(request.into_body())
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+            let source: Source = String::from_utf8_lossy(&body)
+                .trim()
+                .parse()
+                .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+            info!("🔄 Switching to {}", source);
+            for (_, job) in &selected {
+                let source = source.clone();
+                job.rebuild(|job| job.source = source)
+                    .await
+                    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+            }
+        }
+        _ => return Err((StatusCode::NOT_FOUND, "Unknown endpoint".to_owned())),
+    }
+
+    let status: Vec<JobStatus> = selected.iter().map(|(id, job)| job.status(*id)).collect();
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+            serde_json::to_string_pretty(&status).unwrap() + "\n",
+        ))
+        .unwrap())
+}
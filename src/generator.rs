@@ -0,0 +1,243 @@
+use chrono::{Local, Timelike};
+use rand::prelude::*;
+use rayon::prelude::*;
+use std::{
+    error::Error,
+    f64::consts::PI,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use crate::image_data::{Frame, Pixel};
+use crate::text::{self, TextOptions};
+
+/// Built-in procedural sources
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Generator {
+    Solid,
+    Gradient,
+    Plasma,
+    Noise,
+    Clock,
+}
+
+impl FromStr for Generator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "solid" => Ok(Generator::Solid),
+            "gradient" => Ok(Generator::Gradient),
+            "plasma" => Ok(Generator::Plasma),
+            "noise" => Ok(Generator::Noise),
+            "clock" => Ok(Generator::Clock),
+            _ => Err(format!(
+                "Unknown generator '{}', expected solid, gradient, plasma, noise or clock",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorOptions {
+    pub generator: Generator,
+    /// Size of the generated image, the clock sizes itself to its text
+    pub size: (u32, u32),
+    /// Number of frames of animated generators
+    pub frames: u32,
+    pub color: (u8, u8, u8),
+    pub color2: (u8, u8, u8),
+    /// Seed for the noise, a random one is used if none is given
+    pub seed: Option<u64>,
+    /// Font and colours of the clock
+    pub text: TextOptions,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        GeneratorOptions {
+            generator: Generator::Solid,
+            size: (64, 64),
+            frames: 32,
+            color: (255, 255, 255),
+            color2: (0, 0, 0),
+            seed: None,
+            text: TextOptions::default(),
+        }
+    }
+}
+
+/// Generates the frames of a procedural source
+pub fn generate(options: &GeneratorOptions) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let (width, height) = options.size;
+    if width == 0 || height == 0 {
+        return Err("The generator size must not be empty".into());
+    }
+    let frames = options.frames.max(1);
+    Ok(match options.generator {
+        Generator::Solid => {
+            let (r, g, b) = options.color;
+            vec![Frame::new(
+                vec![Pixel::Rgb(r, g, b); (width * height) as usize],
+                (0, 0),
+                options.size,
+                10,
+            )]
+        }
+        Generator::Gradient => vec![render(options.size, 10, |x, _y| {
+            let t = x as f64 / (width - 1).max(1) as f64;
+            lerp(options.color, options.color2, t)
+        })],
+        Generator::Plasma => (0..frames)
+            .map(|frame| {
+                let t = 2.0 * PI * frame as f64 / frames as f64;
+                render(options.size, 1, |x, y| {
+                    let (x, y) = (x as f64, y as f64);
+                    let v = ((x / 16.0 + t).sin()
+                        + (y / 8.0 + t).sin()
+                        + ((x + y) / 16.0 + t).sin()
+                        + ((x * x + y * y).sqrt() / 8.0 + t).sin())
+                        / 4.0;
+                    let channel = |phase: f64| (127.5 + 127.5 * (PI * v + phase).sin()) as u8;
+                    Pixel::Rgb(
+                        channel(0.0),
+                        channel(2.0 * PI / 3.0),
+                        channel(4.0 * PI / 3.0),
+                    )
+                })
+            })
+            .collect(),
+        Generator::Noise => {
+            let perlin = Perlin::new(&mut match options.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            });
+            (0..frames)
+                .map(|frame| {
+                    // Moving the sample window in a circle makes the animation loop
+                    let t = 2.0 * PI * frame as f64 / frames as f64;
+                    let (dx, dy) = (8.0 * t.cos(), 8.0 * t.sin());
+                    render(options.size, 1, |x, y| {
+                        let n = perlin.fractal((x as f64 + dx) / 32.0, (y as f64 + dy) / 32.0, 4);
+                        lerp(options.color, options.color2, n)
+                    })
+                })
+                .collect()
+        }
+        Generator::Clock => clock(&options.text)?,
+    })
+}
+
+/// Renders the local time as `HH:MM` once for every minute of the day, starting at midnight
+///
+/// The frames are played at the wall-clock time they are due, counted from
+/// `clock_epoch`, so the clock is right however late playback starts.
+fn clock(text_options: &TextOptions) -> Result<Vec<Frame>, Box<dyn Error>> {
+    const MINUTE: u16 = 6000;
+    let mut frames = Vec::with_capacity(1440);
+    for minute in 0..1440 {
+        let mut frame = text::render_text(&TextOptions {
+            text: format!("{:02}:{:02}", minute / 60, minute % 60),
+            // Changed digits have to be erased, so the clock needs a background
+            background: Some(text_options.background.unwrap_or((0, 0, 0))),
+            marquee: None,
+            ..text_options.clone()
+        })?
+        .remove(0);
+        frame.set_delay(MINUTE);
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// Start of the local day, when the first frame of the clock is due
+pub fn clock_epoch() -> SystemTime {
+    let now = Local::now();
+    let today = Duration::from_secs(now.num_seconds_from_midnight() as u64)
+        + Duration::from_nanos(now.nanosecond().min(999_999_999) as u64);
+    SystemTime::from(now) - today
+}
+
+fn render<F>(size: (u32, u32), delay: u16, pixel: F) -> Frame
+where
+    F: Fn(u32, u32) -> Pixel + Sync,
+{
+    let image = (0..size.0 * size.1)
+        .into_par_iter()
+        .map(|i| pixel(i % size.0, i / size.0))
+        .collect();
+    Frame::new(image, (0, 0), size, delay)
+}
+
+fn lerp(a: (u8, u8, u8), b: (u8, u8, u8), t: f64) -> Pixel {
+    let channel = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    Pixel::Rgb(channel(a.0, b.0), channel(a.1, b.1), channel(a.2, b.2))
+}
+
+/// Ken Perlin's improved gradient noise
+struct Perlin {
+    permutation: [u8; 512],
+}
+
+impl Perlin {
+    fn new<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let mut values: Vec<u8> = (0..=255).collect();
+        values.shuffle(rng);
+        let mut permutation = [0; 512];
+        for (i, p) in permutation.iter_mut().enumerate() {
+            *p = values[i % 256];
+        }
+        Perlin { permutation }
+    }
+
+    /// Noise at the given position in the range of -1 to 1
+    fn noise(&self, x: f64, y: f64) -> f64 {
+        fn fade(t: f64) -> f64 {
+            t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+        }
+        fn grad(hash: u8, x: f64, y: f64) -> f64 {
+            match hash & 7 {
+                0 => x + y,
+                1 => -x + y,
+                2 => x - y,
+                3 => -x - y,
+                4 => x,
+                5 => -x,
+                6 => y,
+                _ => -y,
+            }
+        }
+        fn mix(a: f64, b: f64, t: f64) -> f64 {
+            a + t * (b - a)
+        }
+
+        let p = &self.permutation;
+        let (xi, yi) = (x.floor() as i64 & 255, y.floor() as i64 & 255);
+        let (xf, yf) = (x - x.floor(), y - y.floor());
+        let (u, v) = (fade(xf), fade(yf));
+        let a = p[xi as usize] as usize + yi as usize;
+        let b = p[xi as usize + 1] as usize + yi as usize;
+        mix(
+            mix(grad(p[a], xf, yf), grad(p[b], xf - 1.0, yf), u),
+            mix(
+                grad(p[a + 1], xf, yf - 1.0),
+                grad(p[b + 1], xf - 1.0, yf - 1.0),
+                u,
+            ),
+            v,
+        )
+    }
+
+    /// Sum of several octaves of noise scaled to the range of 0 to 1
+    fn fractal(&self, x: f64, y: f64, octaves: u32) -> f64 {
+        let (mut sum, mut amplitude, mut frequency, mut max) = (0.0, 1.0, 1.0, 0.0);
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(x * frequency, y * frequency);
+            max += amplitude;
+            amplitude /= 2.0;
+            frequency *= 2.0;
+        }
+        ((sum / max + 1.0) / 2.0).clamp(0.0, 1.0)
+    }
+}
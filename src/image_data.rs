@@ -1,26 +1,29 @@
+use log::warn;
 use rand::prelude::*;
 use rayon::prelude::*;
 use smallvec::SmallVec;
-use std::io::Read;
-use std::{fs::File, io::Write};
+use std::io::{Read, Stdin};
+use std::{
+    fs::File,
+    io::Write,
+    ops::Range,
+    time::{Duration, SystemTime},
+};
 
 #[derive(Debug)]
 pub enum GifSource {
     File(File),
     Vec(Vec<u8>),
+    Stdin(Stdin),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Pixel {
+    #[default]
     Empty,
     Rgb(u8, u8, u8),
 }
 
-impl Default for Pixel {
-    fn default() -> Self {
-        Self::Empty
-    }
-}
 impl Pixel {
     #[inline]
     pub fn combine(self, other: Self, similarity: u32) -> Self {
@@ -40,6 +43,11 @@ impl Pixel {
     }
     /// Calculates the similarity of 2 pixels
     pub fn similarity(self, other: Self) -> u32 {
+        #[inline]
+        fn abs_diff(a: u8, b: u8) -> u8 {
+            (a > b) as u8 * (a.overflowing_sub(b).0) + (a < b) as u8 * (b.overflowing_sub(a).0)
+        }
+
         if self == other {
             return 0;
         }
@@ -51,23 +59,26 @@ impl Pixel {
                     / 255;
             }
         }
-        return u32::MAX;
-
-        #[inline]
-        fn abs_diff(a: u8, b: u8) -> u8 {
-            return (a > b) as u8 * (a.overflowing_sub(b).0)
-                + (a < b) as u8 * (b.overflowing_sub(a).0);
-        }
+        u32::MAX
     }
     pub fn rgb_to_hex(rgb: (u8, u8, u8)) -> [u8; 6] {
-        let combined: [[u8; 2]; 3] = [hex_str(rgb.0), hex_str(rgb.1), hex_str(rgb.2)];
-        return unsafe { std::mem::transmute(combined) };
-
         #[inline]
         fn hex_str(a: u8) -> [u8; 2] {
             let lookup = b"0123456789abcdef";
             [lookup[(a / 16) as usize], lookup[(a & 0xF) as usize]]
         }
+
+        let combined: [[u8; 2]; 3] = [hex_str(rgb.0), hex_str(rgb.1), hex_str(rgb.2)];
+        unsafe { std::mem::transmute::<[[u8; 2]; 3], [u8; 6]>(combined) }
+    }
+    /// Parses a colour in the format `rrggbb`, optionally prefixed with `#`
+    pub fn hex_to_rgb(hex: &str) -> Option<(u8, u8, u8)> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        Some((channel(0)?, channel(2)?, channel(4)?))
     }
 }
 
@@ -80,22 +91,224 @@ pub struct Frame {
     delay: u16,
 }
 
+/// Number of correction commands kept over all frames when they are
+/// multiplied, by motion steps or tiled copies
+pub const CORRECTION_BUDGET: usize = 1 << 21;
+
+/// Position and colour of a pixel that is redrawn while a frame is shown
+pub type Correction = (u32, u32, (u8, u8, u8));
+
+/// Frame, correction instructions and delay in 10ms
+pub type FrameInstructions = (Vec<u8>, Vec<SmallVec<[u8; 18]>>, u16);
+
 #[derive(Debug, Clone)]
 pub struct OptimizedImage {
     pub start: Frame,
     pub frames: Vec<Frame>,
-    pub corrections: Vec<Vec<(u32, u32, (u8, u8, u8))>>,
+    pub corrections: Vec<Vec<Correction>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct FlutInstructions {
     /// Start frame instructions
     pub start: Vec<u8>,
-    /// Frame, correction instructions and delay in 10ms
-    pub frames: Vec<(Vec<u8>, Vec<SmallVec<[u8; 18]>>, u16)>,
+    pub frames: Vec<FrameInstructions>,
+    /// Indices of the frames in the order they are played, frames may repeat
+    pub sequence: Vec<usize>,
+    /// Whether the delay of each frame is real time, not scaled by the time factor
+    pub fixed_delays: Vec<bool>,
+    /// Parts of the sequence that are repeated for a time instead of a number of times
+    pub timed_loops: Vec<TimedLoop>,
+    /// Wall-clock time at which the sequence started, frames that show the
+    /// time are played when they are due instead of one after another
+    pub epoch: Option<SystemTime>,
+}
+
+/// Loop of the sequence that is repeated as often as fits into a duration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedLoop {
+    /// Positions of one loop in the sequence
+    pub positions: Range<usize>,
+    /// Time the loop is repeated for in 10ms, not scaled by the time factor
+    pub duration: u64,
+}
+
+impl FlutInstructions {
+    /// Time the frame with the index is shown for
+    pub fn delay(&self, index: usize, time_factor: u64) -> Duration {
+        let delay = self.frames[index].2 as u64 * 10;
+        Duration::from_millis(match self.fixed_delays[index] {
+            true => delay,
+            false => delay * time_factor,
+        })
+    }
+
+    /// Position in the sequence that is played after the given one
+    ///
+    /// `repeats` counts how often the timed loop that ends there was played
+    /// again, the loop is repeated as long as that ends closer to its duration.
+    pub fn next_position(&self, position: usize, repeats: &mut u32, time_factor: u64) -> usize {
+        let timed = self
+            .timed_loops
+            .iter()
+            .find(|timed| timed.positions.end == position + 1);
+        if let Some(timed) = timed {
+            let loop_time: Duration = timed
+                .positions
+                .clone()
+                .map(|position| self.delay(self.sequence[position], time_factor))
+                .sum();
+            let loops = (timed.duration * 10) as f64 / loop_time.as_millis().max(1) as f64;
+            if *repeats + 1 < loops.round() as u32 {
+                *repeats += 1;
+                return timed.positions.start;
+            }
+            *repeats = 0;
+        }
+        (position + 1) % self.sequence.len()
+    }
+
+    /// Position in the sequence of the frame that is due at the time and how
+    /// much longer it is shown, none if the frames don't follow the wall clock
+    pub fn due_at(&self, now: SystemTime, time_factor: u64) -> Option<(usize, Duration)> {
+        let epoch = self.epoch?;
+        let delay =
+            |position: usize| self.delay(self.sequence[position], time_factor).as_millis() as u64;
+        let total: u64 = (0..self.sequence.len()).map(delay).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut time = now.duration_since(epoch).unwrap_or_default().as_millis() as u64 % total;
+        for position in 0..self.sequence.len() {
+            if time < delay(position) {
+                return Some((position, Duration::from_millis(delay(position) - time)));
+            }
+            time -= delay(position);
+        }
+        None
+    }
+}
+
+/// Positions at which copies of an image are drawn
+#[derive(Debug, Clone)]
+pub struct Tiling {
+    pub offsets: Vec<(u32, u32)>,
+    /// Pixels at or beyond this position are left out
+    pub end: (u32, u32),
+}
+
+impl Tiling {
+    /// A single copy at the offset
+    pub fn single(offset: (u32, u32)) -> Self {
+        Tiling {
+            offsets: vec![offset],
+            end: (u32::MAX, u32::MAX),
+        }
+    }
+    /// Copies of a tile filling the area at the offset, cut off at its edges
+    pub fn grid(offset: (u32, u32), tile: (u32, u32), area: (u32, u32)) -> Self {
+        let (width, height) = (tile.0.max(1), tile.1.max(1));
+        let offsets = (0..area.1.div_ceil(height))
+            .flat_map(|row| {
+                (0..area.0.div_ceil(width))
+                    .map(move |column| (offset.0 + column * width, offset.1 + row * height))
+            })
+            .collect();
+        Tiling {
+            offsets,
+            end: (offset.0 + area.0, offset.1 + area.1),
+        }
+    }
+    /// Positions of a pixel in all copies
+    fn positions(&self, x: u32, y: u32) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (0..self.offsets.len()).filter_map(move |copy| self.position(x, y, copy))
+    }
+    /// Position of a pixel in one copy, none if it is cut off
+    fn position(&self, x: u32, y: u32, copy: usize) -> Option<(u32, u32)> {
+        let (x, y) = (x + self.offsets[copy].0, y + self.offsets[copy].1);
+        Some((x, y)).filter(|&(x, y)| x < self.end.0 && y < self.end.1)
+    }
 }
 
 impl Frame {
+    /// Creates a frame from its pixels in row-major order
+    pub fn new(image: Vec<Pixel>, offset: (u32, u32), size: (u32, u32), delay: u16) -> Self {
+        debug_assert_eq!(image.len(), (size.0 * size.1) as usize);
+        Frame {
+            image,
+            offset,
+            size,
+            delay,
+        }
+    }
+    /// Position of the top left pixel
+    pub fn offset(&self) -> (u32, u32) {
+        self.offset
+    }
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+    /// Bottom right corner, exclusive
+    pub fn end(&self) -> (u32, u32) {
+        (self.offset.0 + self.size.0, self.offset.1 + self.size.1)
+    }
+    /// Pixel at the given position, empty outside of the frame
+    pub fn get(&self, x: u32, y: u32) -> Pixel {
+        let (end_x, end_y) = self.end();
+        if x < self.offset.0 || y < self.offset.1 || x >= end_x || y >= end_y {
+            return Pixel::Empty;
+        }
+        let (x, y) = (x - self.offset.0, y - self.offset.1);
+        self.image[(x + self.size.0 * y) as usize]
+    }
+    /// Frame delay in 10ms
+    pub fn delay(&self) -> u16 {
+        self.delay
+    }
+    /// Sets the frame delay in 10ms
+    pub fn set_delay(&mut self, delay: u16) {
+        self.delay = delay;
+    }
+    /// Replaces every pixel with the result of the function
+    pub fn map_pixels<F: Fn(Pixel) -> Pixel + Sync>(&mut self, f: F) {
+        self.image
+            .par_iter_mut()
+            .for_each(|pixel| *pixel = f(*pixel));
+    }
+    /// Replaces every pixel with the result of the function, which also gets
+    /// the position of the pixel
+    pub fn map_positioned_pixels<F: Fn((u32, u32), Pixel) -> Pixel + Sync>(&mut self, f: F) {
+        let (offset, width) = (self.offset, self.size.0);
+        self.image
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(i, pixel)| {
+                let (x, y) = (i as u32 % width, i as u32 / width);
+                *pixel = f((x + offset.0, y + offset.1), *pixel)
+            });
+    }
+    /// Moves the frame by the given offset
+    pub fn translate(&mut self, offset: (u32, u32)) {
+        self.offset = (self.offset.0 + offset.0, self.offset.1 + offset.1);
+    }
+    /// Scales the frame up by an integer factor using nearest-neighbour sampling
+    pub fn scale(&self, factor: u32) -> Self {
+        let size = (self.size.0 * factor, self.size.1 * factor);
+        let image = (0..size.0 * size.1)
+            .into_par_iter()
+            .map(|i| {
+                let x = (i % size.0) / factor;
+                let y = (i / size.0) / factor;
+                self.image[(x + self.size.0 * y) as usize]
+            })
+            .collect();
+        Frame {
+            image,
+            offset: (self.offset.0 * factor, self.offset.1 * factor),
+            size,
+            delay: self.delay,
+        }
+    }
     pub fn combine(&self, other: &Self, similarity: u32) -> Self {
         let new_offset = (
             self.offset.0.min(other.offset.0),
@@ -154,55 +367,69 @@ impl Frame {
             delay: other.delay,
         }
     }
+    /// Visible pixels with their position
+    fn pixels(&self) -> impl Iterator<Item = Correction> + '_ {
+        self.image
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, &pixel)| {
+                if let Pixel::Rgb(r, g, b) = pixel {
+                    let i = i as u32;
+                    let x = (i % self.size.0) + self.offset.0;
+                    let y = (i / self.size.0) + self.offset.1;
+                    Some((x, y, (r, g, b)))
+                } else {
+                    None
+                }
+            })
+    }
+    /// Draws the frame at every position of the tiling, a pixel is drawn in all
+    /// copies before the next one
     pub fn to_instructions<R: Rng + ?Sized>(
         &self,
-        off_x: u32,
-        off_y: u32,
+        tiling: &Tiling,
         rng_option: &mut Option<&mut R>,
     ) -> Vec<u8> {
-        let off_x = self.offset.0 + off_x;
-        let off_y = self.offset.1 + off_y;
+        let mut pixels: Vec<_> = self.pixels().collect();
         if let Some(rng) = rng_option {
-            let mut buffer = Vec::with_capacity(18 * self.image.len() / 2);
-            let mut pixels: Vec<_> = self.image.par_iter().enumerate().collect();
             pixels.shuffle(*rng);
-            for (i, &pixel) in pixels {
-                if let Pixel::Rgb(r, g, b) = pixel {
-                    let i = i as u32;
-                    let x = (i % self.size.0) + off_x;
-                    let y = (i / self.size.0) + off_y;
-                    write_instruction(&mut buffer, x, y, (r, g, b)).unwrap();
-                }
-            }
-            buffer
-        } else {
-            let mut buffer = Vec::with_capacity(18 * self.image.len() / 2);
-            for (i, &pixel) in self.image.iter().enumerate() {
-                if let Pixel::Rgb(r, g, b) = pixel {
-                    let i = i as u32;
-                    let x = (i % self.size.0) + off_x;
-                    let y = (i / self.size.0) + off_y;
-                    write_instruction(&mut buffer, x, y, (r, g, b)).unwrap();
-                }
+        }
+        let mut buffer = Vec::with_capacity(18 * pixels.len() * tiling.offsets.len());
+        for (x, y, rgb) in pixels {
+            for (x, y) in tiling.positions(x, y) {
+                write_instruction(&mut buffer, x, y, rgb).unwrap();
             }
-            buffer
         }
+        buffer
     }
 }
 
-pub fn load_image<R: Read>(src: R) -> Vec<Frame> {
+/// Decodes the frames of a GIF, which may be streamed from a pipe
+///
+/// If the stream is cut off after the first frame, the frames decoded until
+/// then are returned.
+pub fn load_image<R: Read>(src: R) -> Result<Vec<Frame>, gif::DecodingError> {
     let decode_options = {
         let mut opt = gif::DecodeOptions::new();
         opt.set_color_output(gif::ColorOutput::Indexed);
         opt
     };
 
-    let mut gif_decoder = decode_options.read_info(src).unwrap();
+    let mut gif_decoder = decode_options.read_info(src)?;
     let mut frames = Vec::new();
 
     let g_palette = gif_decoder.global_palette().map(|p| p.to_owned());
 
-    while let Some(frame) = gif_decoder.read_next_frame().unwrap() {
+    loop {
+        let frame = match gif_decoder.read_next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(err) if !frames.is_empty() => {
+                warn!("⚠️ GIF stream ended early: {}", err);
+                break;
+            }
+            Err(err) => return Err(err),
+        };
         let mut pixels = Vec::with_capacity(frame.buffer.len());
         let palette = frame
             .palette
@@ -210,7 +437,7 @@ pub fn load_image<R: Read>(src: R) -> Vec<Frame> {
             .unwrap_or_else(|| g_palette.as_ref().unwrap());
         for i in 0..pixels.capacity() {
             let idx = frame.buffer[i] as usize;
-            pixels.push(if frame.transparent.map_or(false, |t| t == idx as u8) {
+            pixels.push(if frame.transparent == Some(idx as u8) {
                 Pixel::Empty
             } else {
                 let rgb = &palette[(3 * idx)..(3 * idx + 3)];
@@ -225,7 +452,7 @@ pub fn load_image<R: Read>(src: R) -> Vec<Frame> {
         });
     }
 
-    frames
+    Ok(frames)
 }
 
 /// Removes unchanged pixels from frames
@@ -239,8 +466,8 @@ pub fn optimize_image(frames: Vec<Frame>, similarity: u32) -> OptimizedImage {
     }
     optimized_frames.push(start.clone());
 
-    for i in 1..frames.len() {
-        intermediate = intermediate.combine(&frames[i], similarity);
+    for frame in frames.iter().skip(1) {
+        intermediate = intermediate.combine(frame, similarity);
     }
     intermediate = intermediate.combine(&frames[0], similarity);
 
@@ -276,7 +503,7 @@ pub fn optimize_image(frames: Vec<Frame>, similarity: u32) -> OptimizedImage {
             .image
             .iter()
             .enumerate()
-            .map(|(i, &pixel)| {
+            .filter_map(|(i, &pixel)| {
                 let i = i as u32;
                 let lx = i % intermediate.size.0;
                 let ly = i / intermediate.size.0;
@@ -314,8 +541,6 @@ pub fn optimize_image(frames: Vec<Frame>, similarity: u32) -> OptimizedImage {
                     }
                 }
             })
-            .filter(Option::is_some)
-            .map(Option::unwrap)
             .collect();
         corrections[i] = correction;
         intermediate = intermediate.combine(cmp, similarity);
@@ -327,68 +552,75 @@ pub fn optimize_image(frames: Vec<Frame>, similarity: u32) -> OptimizedImage {
     }
 }
 
+/// Generates the instructions of all frames for every copy of the tiling
+///
+/// Corrections are ordered pixel by pixel across the copies, so grief is
+/// repaired evenly instead of one copy after another.
 pub fn optimized_image_to_instructions<R: Rng + ?Sized>(
     image: OptimizedImage,
-    off_x: u32,
-    off_y: u32,
+    tiling: &Tiling,
     rng_option: &mut Option<&mut R>,
 ) -> FlutInstructions {
+    let start = image.start.to_instructions(tiling, rng_option);
+    let budget = CORRECTION_BUDGET / image.frames.len().max(1);
+    let frames: Vec<FrameInstructions> = image
+        .frames
+        .iter()
+        .zip(image.corrections)
+        .enumerate()
+        .map(|(index, (frame, corrections))| {
+            let mut pixels = corrections;
+            pixels.extend(frame.pixels());
+            if let Some(rng) = rng_option {
+                pixels.shuffle(*rng);
+            }
+            // Many copies are corrected in turns, but each pixel at least once
+            let kept = budget.max(pixels.len());
+            (
+                frame.to_instructions(tiling, rng_option),
+                tiled_corrections(&pixels, tiling, index, kept),
+                frame.delay,
+            )
+        })
+        .collect();
     FlutInstructions {
-        start: image.start.to_instructions(off_x, off_y, rng_option),
-        frames: image
-            .frames
-            .iter()
-            .zip(image.corrections)
-            .map(|(frame, corrections)| {
-                (
-                    frame.to_instructions(off_x, off_y, rng_option),
-                    {
-                        let mut cr: Vec<_> = corrections
-                            .par_iter()
-                            .map(|&(x, y, rgb)| {
-                                let mut b: SmallVec<[u8; 18]> = SmallVec::new();
-                                write_instruction_smallvec(&mut b, x + off_x, y + off_y, rgb);
-                                b
-                            })
-                            .chain(
-                                frame
-                                    .image
-                                    .par_iter()
-                                    .enumerate()
-                                    .map(|(i, pixel)| {
-                                        let i = i as u32;
-                                        let x = (i % frame.size.0) + frame.offset.0 + off_x;
-                                        let y = (i / frame.size.0) + frame.offset.1 + off_y;
-                                        (x, y, pixel)
-                                    })
-                                    .filter(|(_x, _y, &pixel)| match pixel {
-                                        Pixel::Empty => false,
-                                        _ => true,
-                                    })
-                                    .map(|(x, y, &pixel)| {
-                                        if let Pixel::Rgb(r, g, b) = pixel {
-                                            let mut bytes: SmallVec<[u8; 18]> = SmallVec::new();
-                                            write_instruction_smallvec(&mut bytes, x, y, (r, g, b));
-                                            bytes
-                                        } else {
-                                            unreachable!();
-                                        }
-                                    }),
-                            )
-                            .collect();
-                        if let Some(rng) = rng_option {
-                            cr.shuffle(*rng);
-                        }
-                        cr
-                    },
-                    frame.delay,
-                )
-            })
-            .collect(),
+        start,
+        sequence: (0..frames.len()).collect(),
+        fixed_delays: vec![false; frames.len()],
+        timed_loops: Vec::new(),
+        frames,
+        epoch: None,
     }
 }
 
-fn write_instruction<W: Write>(
+/// Correction commands of the pixels in every copy of the tiling, pixel by
+/// pixel across the copies
+///
+/// If there are more than `kept`, the frame with the index only gets its
+/// share of them and the following frames continue where it ended.
+fn tiled_corrections(
+    pixels: &[Correction],
+    tiling: &Tiling,
+    index: usize,
+    kept: usize,
+) -> Vec<SmallVec<[u8; 18]>> {
+    let copies = tiling.offsets.len();
+    let total = pixels.len() * copies;
+    let kept = kept.min(total);
+    (0..kept)
+        .into_par_iter()
+        .filter_map(|i| {
+            let i = (index * kept + i) % total;
+            let (x, y, rgb) = pixels[i / copies];
+            let (x, y) = tiling.position(x, y, i % copies)?;
+            let mut b: SmallVec<[u8; 18]> = SmallVec::new();
+            write_instruction_smallvec(&mut b, x, y, rgb);
+            Some(b)
+        })
+        .collect()
+}
+
+pub fn write_instruction<W: Write>(
     buffer: &mut W,
     x: u32,
     y: u32,
@@ -396,7 +628,7 @@ fn write_instruction<W: Write>(
 ) -> std::io::Result<usize> {
     let mut c = buffer.write(format!("PX {} {} ", x, y).as_bytes())?;
     c += buffer.write(&Pixel::rgb_to_hex(rgb))?;
-    c += buffer.write(&[b'\n'])?;
+    c += buffer.write(b"\n")?;
     Ok(c)
 }
 
@@ -410,3 +642,125 @@ fn write_instruction_smallvec<const N: usize>(
     buffer.extend_from_slice(&Pixel::rgb_to_hex(rgb));
     buffer.push(b'\n');
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(corrections: &[SmallVec<[u8; 18]>]) -> Vec<String> {
+        corrections
+            .iter()
+            .map(|command| String::from_utf8(command.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn corrections_of_all_copies() {
+        let pixels = [(0, 0, (255, 0, 0)), (1, 0, (0, 0, 255))];
+        // Three copies of a 2x1 tile, the last one is cut off after one pixel
+        let tiling = Tiling::grid((10, 0), (2, 1), (5, 1));
+        assert_eq!(
+            commands(&tiled_corrections(&pixels, &tiling, 0, usize::MAX)),
+            [
+                "PX 10 0 ff0000\n",
+                "PX 12 0 ff0000\n",
+                "PX 14 0 ff0000\n",
+                "PX 11 0 0000ff\n",
+                "PX 13 0 0000ff\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn corrections_beyond_the_budget_take_turns() {
+        let pixels = [(0, 0, (255, 0, 0)), (1, 0, (0, 0, 255))];
+        let tiling = Tiling::grid((0, 0), (2, 1), (6, 1));
+        let first = commands(&tiled_corrections(&pixels, &tiling, 0, 4));
+        let second = commands(&tiled_corrections(&pixels, &tiling, 1, 4));
+        assert_eq!(
+            first,
+            [
+                "PX 0 0 ff0000\n",
+                "PX 2 0 ff0000\n",
+                "PX 4 0 ff0000\n",
+                "PX 1 0 0000ff\n",
+            ]
+        );
+        assert_eq!(
+            second,
+            [
+                "PX 3 0 0000ff\n",
+                "PX 5 0 0000ff\n",
+                "PX 0 0 ff0000\n",
+                "PX 2 0 ff0000\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn frames_due_on_the_wall_clock() {
+        let frame = |delay| (Vec::new(), Vec::new(), delay);
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let commands = FlutInstructions {
+            start: Vec::new(),
+            frames: vec![frame(100), frame(50)],
+            sequence: vec![0, 1, 0],
+            fixed_delays: vec![false; 2],
+            timed_loops: Vec::new(),
+            epoch: Some(epoch),
+        };
+        let at = |ms| commands.due_at(epoch + Duration::from_millis(ms), 1);
+        assert_eq!(at(0), Some((0, Duration::from_secs(1))));
+        assert_eq!(at(999), Some((0, Duration::from_millis(1))));
+        assert_eq!(at(1000), Some((1, Duration::from_millis(500))));
+        assert_eq!(at(1600), Some((2, Duration::from_millis(900))));
+        // The sequence loops every 2.5s
+        assert_eq!(at(25_000 + 1200), Some((1, Duration::from_millis(300))));
+        assert_eq!(
+            commands.due_at(epoch + Duration::from_millis(1200), 10),
+            Some((0, Duration::from_millis(8800)))
+        );
+
+        let commands = FlutInstructions {
+            epoch: None,
+            ..commands
+        };
+        assert_eq!(commands.due_at(epoch, 1), None);
+    }
+
+    #[test]
+    fn timed_loops_follow_the_time_factor() {
+        let frame = |delay| (Vec::new(), Vec::new(), delay);
+        // A 100ms loop shown for one second, then a fixed 50ms transition
+        let commands = FlutInstructions {
+            start: Vec::new(),
+            frames: vec![frame(5), frame(5), frame(5)],
+            sequence: vec![0, 1, 2],
+            fixed_delays: vec![false, false, true],
+            timed_loops: vec![TimedLoop {
+                positions: 0..2,
+                duration: 100,
+            }],
+            epoch: None,
+        };
+        assert_eq!(commands.delay(0, 10), Duration::from_millis(500));
+        assert_eq!(commands.delay(2, 10), Duration::from_millis(50));
+
+        let loops = |time_factor| {
+            let (mut position, mut repeats, mut loops) = (0, 0, 1);
+            while position != 2 {
+                let next = commands.next_position(position, &mut repeats, time_factor);
+                if next < position {
+                    loops += 1;
+                }
+                position = next;
+            }
+            assert_eq!(commands.next_position(2, &mut repeats, time_factor), 0);
+            loops
+        };
+        assert_eq!(loops(1), 10);
+        assert_eq!(loops(3), 3);
+        assert_eq!(loops(10), 1);
+        assert_eq!(loops(100), 1);
+    }
+}
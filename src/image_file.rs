@@ -0,0 +1,211 @@
+use rayon::prelude::*;
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::image_data::{Frame, Pixel};
+
+/// Errors of the decoders, which may run on the thread pool
+type DecodeError = Box<dyn Error + Send + Sync>;
+
+/// Loads a PNG or PPM image as a single frame
+pub fn load_still(path: &Path) -> Result<Frame, Box<dyn Error>> {
+    decode_still(path).map_err(|err| err as Box<dyn Error>)
+}
+
+fn decode_still(path: &Path) -> Result<Frame, DecodeError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 2];
+    reader.read_exact(&mut magic)?;
+    let mut data = magic.to_vec();
+    reader.read_to_end(&mut data)?;
+    match &magic {
+        b"P3" | b"P6" => load_ppm(&data),
+        [0x89, b'P'] => load_png(&data),
+        _ => Err(format!("{} is neither a PNG nor a PPM image", path.display()).into()),
+    }
+}
+
+/// Loads a numbered sequence of PNG or PPM images with a uniform frame rate
+pub fn load_frames_dir(dir: &Path, fps: f32) -> Result<Vec<Frame>, Box<dyn Error>> {
+    if fps.is_nan() || fps <= 0.0 {
+        return Err(format!("Invalid frame rate {}, expected a positive number", fps).into());
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext.to_ascii_lowercase().as_str(), "png" | "ppm"))
+    });
+    if paths.is_empty() {
+        return Err(format!("{} contains no PNG or PPM images", dir.display()).into());
+    }
+    paths.sort_by_cached_key(|path| {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        (frame_number(&name), name)
+    });
+
+    let delay = (100.0 / fps).round().clamp(1.0, u16::MAX as f32) as u16;
+    paths
+        .par_iter()
+        .map(|path| {
+            let mut frame = decode_still(path)?;
+            frame.set_delay(delay);
+            Ok(frame)
+        })
+        .collect::<Result<_, DecodeError>>()
+        .map_err(|err| err as Box<dyn Error>)
+}
+
+/// Last number in the file name, e.g. 12 for `frame-0012.png`
+fn frame_number(name: &str) -> Option<u64> {
+    name.split(|c: char| !c.is_ascii_digit())
+        .rfind(|digits| !digits.is_empty())
+        .and_then(|digits| digits.parse().ok())
+}
+
+fn load_png(data: &[u8]) -> Result<Frame, DecodeError> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let samples = info.color_type.samples();
+    let image = buffer[..info.buffer_size()]
+        .chunks_exact(samples)
+        .map(|px| match info.color_type {
+            png::ColorType::Grayscale => Pixel::Rgb(px[0], px[0], px[0]),
+            png::ColorType::GrayscaleAlpha if px[1] < 128 => Pixel::Empty,
+            png::ColorType::GrayscaleAlpha => Pixel::Rgb(px[0], px[0], px[0]),
+            png::ColorType::Rgba if px[3] < 128 => Pixel::Empty,
+            _ => Pixel::Rgb(px[0], px[1], px[2]),
+        })
+        .collect();
+    Ok(Frame::new(image, (0, 0), (info.width, info.height), 0))
+}
+
+/// Loads a binary (P6) or plain (P3) portable pixmap
+fn load_ppm(data: &[u8]) -> Result<Frame, DecodeError> {
+    let mut pos = 0;
+    let mut header = [0; 4];
+    for (i, value) in header.iter_mut().enumerate() {
+        let token = next_token(data, &mut pos).ok_or("Truncated PPM header")?;
+        *value = if i == 0 {
+            0
+        } else {
+            std::str::from_utf8(token)?.parse()?
+        };
+    }
+    let [_, width, height, max] = header;
+    if max == 0 || max > 65535 {
+        return Err("Invalid PPM maximum value".into());
+    }
+    let scale = |value: u32| (value.min(max) * 255 / max) as u8;
+    let count = (width as usize)
+        .checked_mul(height as usize)
+        .filter(|count| count.checked_mul(3).is_some())
+        .ok_or("The PPM image is too large")?;
+
+    let samples: Vec<u32> = if data[1] == b'6' {
+        // A single whitespace character separates the header from the raster
+        let raster = data.get(pos + 1..).unwrap_or_default();
+        if max < 256 {
+            raster.iter().take(count * 3).map(|&v| v as u32).collect()
+        } else {
+            raster
+                .chunks_exact(2)
+                .take(count * 3)
+                .map(|v| u16::from_be_bytes([v[0], v[1]]) as u32)
+                .collect()
+        }
+    } else {
+        // Every sample takes at least two bytes, so a lying header can't reserve more
+        let mut samples = Vec::with_capacity((count * 3).min(data.len() / 2));
+        while let Some(token) = next_token(data, &mut pos) {
+            samples.push(std::str::from_utf8(token)?.parse()?);
+        }
+        samples
+    };
+    if samples.len() < count * 3 {
+        return Err("Truncated PPM raster".into());
+    }
+    let image = samples
+        .chunks_exact(3)
+        .take(count)
+        .map(|rgb| Pixel::Rgb(scale(rgb[0]), scale(rgb[1]), scale(rgb[2])))
+        .collect();
+    Ok(Frame::new(image, (0, 0), (width, height), 0))
+}
+
+/// Reads the next whitespace separated token, skipping `#` comments
+fn next_token<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    loop {
+        match data.get(*pos)? {
+            b'#' => {
+                while data.get(*pos).is_some_and(|&c| c != b'\n') {
+                    *pos += 1;
+                }
+            }
+            c if c.is_ascii_whitespace() => *pos += 1,
+            _ => break,
+        }
+    }
+    let start = *pos;
+    while data.get(*pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+    Some(&data[start..*pos])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_ppm() {
+        let frame = load_ppm(b"P3\n# comment\n2 1\n255\n255 0 0  0 128 255\n").unwrap();
+        assert_eq!(frame.size(), (2, 1));
+        assert_eq!(frame.get(0, 0), Pixel::Rgb(255, 0, 0));
+        assert_eq!(frame.get(1, 0), Pixel::Rgb(0, 128, 255));
+    }
+
+    #[test]
+    fn binary_ppm_with_large_maximum() {
+        let mut data = b"P6 1 1 65535\n".to_vec();
+        data.extend_from_slice(&[0xff, 0xff, 0x80, 0x00, 0x00, 0x00]);
+        let frame = load_ppm(&data).unwrap();
+        assert_eq!(frame.get(0, 0), Pixel::Rgb(255, 127, 0));
+    }
+
+    #[test]
+    fn samples_above_maximum_are_clamped() {
+        let frame = load_ppm(b"P3 1 1 15 15 99999999 0").unwrap();
+        assert_eq!(frame.get(0, 0), Pixel::Rgb(255, 255, 0));
+    }
+
+    #[test]
+    fn hostile_ppm_header() {
+        for data in &[
+            &b"P6 4294967295 4294967295 255\n"[..],
+            b"P3 4294967295 4294967295 255 1 2 3",
+            b"P6 100 100 255\n\0\0\0",
+            b"P6 1 1 0\n\0\0\0",
+            b"P6 1 1",
+        ] {
+            assert!(load_ppm(data).is_err(), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn invalid_frame_rate() {
+        for &fps in &[0.0, -10.0, f32::NAN] {
+            let err = load_frames_dir(Path::new("."), fps).unwrap_err();
+            assert!(err.to_string().contains("frame rate"), "{}", err);
+        }
+    }
+}
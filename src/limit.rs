@@ -0,0 +1,212 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+/// Largest burst a bucket allows, as a share of its rate per second
+const BURST: f64 = 0.1;
+
+/// Token bucket refilled continuously at a fixed rate
+#[derive(Debug)]
+pub struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn new(rate: u64) -> Self {
+        let capacity = (rate as f64 * BURST).max(1.0);
+        Bucket {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Adds the tokens earned since the last refill
+    pub fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Time until the bucket holds the amount, or until it is full if the
+    /// amount is more than it can hold
+    pub fn wait(&self, amount: f64) -> Duration {
+        let amount = amount.min(self.capacity);
+        Duration::from_secs_f64(((amount - self.tokens) / self.rate).max(0.0))
+    }
+
+    pub fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+/// Limits of the bytes and pixels per second sent over all connections
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    bytes: Option<Mutex<Bucket>>,
+    pixels: Option<Mutex<Bucket>>,
+}
+
+impl RateLimit {
+    pub fn new(max_bandwidth: Option<u64>, max_pps: Option<u64>) -> Self {
+        RateLimit {
+            bytes: max_bandwidth.map(|rate| Mutex::new(Bucket::new(rate))),
+            pixels: max_pps.map(|rate| Mutex::new(Bucket::new(rate))),
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.bytes.is_some() || self.pixels.is_some()
+    }
+
+    fn buckets(&self, bytes: usize, pixels: u64) -> impl Iterator<Item = (&Mutex<Bucket>, f64)> {
+        self.bytes
+            .iter()
+            .map(move |bucket| (bucket, bytes as f64))
+            .chain(
+                self.pixels
+                    .iter()
+                    .map(move |bucket| (bucket, pixels as f64)),
+            )
+    }
+
+    /// Takes the budget even if it is not available yet and waits until the
+    /// debt is paid off, so frame changes are never skipped
+    pub async fn acquire(&self, bytes: usize, pixels: u64) {
+        let wait = self
+            .buckets(bytes, pixels)
+            .map(|(bucket, amount)| {
+                let mut bucket = bucket.lock().unwrap();
+                bucket.refill(Instant::now());
+                bucket.take(amount);
+                bucket.wait(0.0)
+            })
+            .max();
+        if let Some(wait) = wait.filter(|wait| !wait.is_zero()) {
+            sleep(wait).await;
+        }
+    }
+
+    /// Takes the budget only if it is available or the buckets are full,
+    /// otherwise returns how long to wait for it; corrections only use what
+    /// frame changes left over
+    pub fn try_acquire(&self, bytes: usize, pixels: u64) -> Result<(), Duration> {
+        self.try_acquire_at(bytes, pixels, Instant::now())
+    }
+
+    fn try_acquire_at(&self, bytes: usize, pixels: u64, now: Instant) -> Result<(), Duration> {
+        if !self.is_limited() {
+            return Ok(());
+        }
+        let mut buckets: Vec<_> = self
+            .buckets(bytes, pixels)
+            .map(|(bucket, amount)| (bucket.lock().unwrap(), amount))
+            .collect();
+        for (bucket, _) in &mut buckets {
+            bucket.refill(now);
+        }
+        let wait = buckets
+            .iter()
+            .map(|(bucket, amount)| bucket.wait(*amount))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for (bucket, amount) in &mut buckets {
+            bucket.take(*amount);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Lets all buckets of the limit start at the same time
+    fn start(limit: &RateLimit) -> Instant {
+        let start = Instant::now();
+        for (bucket, _) in limit.buckets(0, 0) {
+            bucket.lock().unwrap().last = start;
+        }
+        start
+    }
+
+    #[test]
+    fn bucket_starts_full() {
+        let mut bucket = Bucket::new(1000);
+        assert_eq!(bucket.capacity, 100.0);
+        assert!(bucket.wait(100.0).is_zero());
+        bucket.take(100.0);
+        assert_eq!(bucket.wait(1.0), ms(1));
+    }
+
+    #[test]
+    fn bucket_waits_for_debt() {
+        let mut bucket = Bucket::new(1000);
+        bucket.take(100.0);
+        assert_eq!(bucket.wait(50.0), ms(50));
+        bucket.take(100.0);
+        assert_eq!(bucket.wait(0.0), ms(100));
+        let start = bucket.last;
+        bucket.refill(start + ms(60));
+        assert_eq!(bucket.wait(0.0), ms(40));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_capacity() {
+        let mut bucket = Bucket::new(1000);
+        bucket.take(100.0);
+        let start = bucket.last;
+        bucket.refill(start + ms(30));
+        assert_eq!(bucket.tokens, 30.0);
+        bucket.refill(start + ms(10_000));
+        assert_eq!(bucket.tokens, bucket.capacity);
+    }
+
+    #[test]
+    fn bucket_holds_at_least_one() {
+        let bucket = Bucket::new(1);
+        assert_eq!(bucket.capacity, 1.0);
+        assert!(bucket.wait(1.0).is_zero());
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let limit = RateLimit::default();
+        assert!(!limit.is_limited());
+        assert_eq!(limit.try_acquire(usize::MAX, u64::MAX), Ok(()));
+    }
+
+    #[test]
+    fn try_acquire_takes_only_what_is_available() {
+        let limit = RateLimit::new(Some(1000), None);
+        let start = start(&limit);
+        assert_eq!(limit.try_acquire_at(60, 1, start), Ok(()));
+        assert_eq!(limit.try_acquire_at(60, 1, start), Err(ms(20)));
+        assert_eq!(limit.try_acquire_at(60, 1, start + ms(5)), Err(ms(15)));
+        assert_eq!(limit.try_acquire_at(60, 1, start + ms(20)), Ok(()));
+    }
+
+    #[test]
+    fn try_acquire_larger_than_capacity_succeeds_when_full() {
+        // A correction of 18 bytes is more than a 100 B/s bucket holds
+        let limit = RateLimit::new(Some(100), Some(5));
+        let start = start(&limit);
+        assert_eq!(limit.try_acquire_at(18, 1, start), Ok(()));
+        // Both buckets need to be full again, the pixels take longer
+        assert_eq!(limit.try_acquire_at(18, 1, start), Err(ms(200)));
+        assert_eq!(limit.try_acquire_at(18, 1, start + ms(200)), Ok(()));
+    }
+}
@@ -0,0 +1,483 @@
+use futures::{lock::Mutex, FutureExt};
+use log::{info, warn};
+use rand::prelude::*;
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, signal, sync::watch, time::timeout};
+
+use crate::image_data::{write_instruction, Pixel};
+use crate::net;
+
+/// Number of pixels redrawn between checks for a new frame
+const CORRECTION_CHUNK: usize = 1024;
+
+/// Format of a raw frame stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LiveFormat {
+    /// Packed 8 bit RGB frames of the given size, as `ffmpeg -f rawvideo -pix_fmt rgb24`
+    Rgb24(u32, u32),
+    /// YUV4MPEG2 stream, the size is read from its header
+    Y4m,
+}
+
+impl FromStr for LiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "y4m" {
+            return Ok(LiveFormat::Y4m);
+        }
+        s.strip_prefix("rgb24:")
+            .and_then(|size| size.split_once('x'))
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+            .filter(|&(w, h)| w > 0 && h > 0)
+            .map(|(w, h)| LiveFormat::Rgb24(w, h))
+            .ok_or_else(|| format!("Unknown live format '{}', expected rgb24:WxH or y4m", s))
+    }
+}
+
+#[derive(Debug)]
+struct LiveFrame {
+    size: (u32, u32),
+    image: Vec<Pixel>,
+}
+
+/// Options of the live flooding loop
+#[derive(Debug, Clone)]
+pub struct LiveOptions {
+    pub format: LiveFormat,
+    pub offset: (u32, u32),
+    pub similarity: u32,
+    pub shuffle: bool,
+}
+
+/// Floods frames from a raw stream as they arrive
+///
+/// Each frame is compared to the pixels drawn so far and only the changed
+/// pixels are sent. While waiting for the next frame, the current frame is
+/// redrawn to fight grief. If the stream is slower than the network, frames
+/// are skipped.
+pub async fn fluten_live(
+    url: &str,
+    input: &str,
+    options: LiveOptions,
+) -> Result<(), Box<dyn Error>> {
+    let reader: Box<dyn Read + Send> = match input {
+        "-" => Box::new(io::stdin()),
+        path => Box::new(File::open(path)?),
+    };
+    let (sender, mut frames) = watch::channel(None);
+    let format = options.format;
+    thread::spawn(move || {
+        if let Err(err) = read_frames(BufReader::new(reader), format, &sender) {
+            warn!("⚠️ Reading the stream failed: {}", err);
+        }
+    });
+
+    info!("📡 Connecting to server...");
+    let mut stream = net::connect_job(url).await?;
+    info!("🌊🌊 Live Flut! 🌊🌊");
+    let stop = Arc::new(Mutex::new(false));
+    let stop2 = stop.clone();
+    tokio::spawn(async move {
+        let _ = signal::ctrl_c().await;
+        info!("🧽 Stopping the Flut...");
+        *stop2.lock().await = true;
+    });
+
+    let mut rng = thread_rng();
+    let mut size = (0, 0);
+    let mut current: Vec<Pixel> = Vec::new();
+    let mut order: Vec<usize> = Vec::new();
+    let mut cursor = 0;
+    let mut ended = false;
+    let mut buffer = Vec::new();
+
+    while !*stop.lock().await {
+        let changed = if ended {
+            None
+        } else if current.is_empty() {
+            // Nothing to redraw yet, so wait for the first frame
+            timeout(Duration::from_millis(100), frames.changed())
+                .await
+                .ok()
+        } else {
+            frames.changed().now_or_never()
+        };
+        match changed {
+            Some(Ok(())) => {
+                let frame = match &*frames.borrow() {
+                    Some(frame) => Arc::clone(frame),
+                    None => continue,
+                };
+                if frame.size != size {
+                    size = frame.size;
+                    current = vec![Pixel::Empty; frame.image.len()];
+                    order = (0..frame.image.len()).collect();
+                    if options.shuffle {
+                        order.shuffle(&mut rng);
+                    }
+                    cursor = 0;
+                }
+                let mut changed: Vec<usize> = frame
+                    .image
+                    .iter()
+                    .zip(current.iter_mut())
+                    .enumerate()
+                    .filter_map(|(i, (&new, old))| {
+                        if old.similarity(new) > options.similarity {
+                            *old = new;
+                            Some(i)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                if options.shuffle {
+                    changed.shuffle(&mut rng);
+                }
+                buffer.clear();
+                for i in changed {
+                    write_pixel(&mut buffer, i, size, options.offset, current[i]);
+                }
+                stream.write_all(&buffer).await?;
+                stream.flush().await?;
+            }
+            Some(Err(_)) if current.is_empty() => {
+                return Err("The stream ended before the first frame".into());
+            }
+            Some(Err(_)) => {
+                info!("📭 Stream ended, holding the last frame");
+                ended = true;
+            }
+            None if current.is_empty() => {}
+            None => {
+                buffer.clear();
+                for _ in 0..CORRECTION_CHUNK.min(order.len()) {
+                    let i = order[cursor];
+                    write_pixel(&mut buffer, i, size, options.offset, current[i]);
+                    cursor = (cursor + 1) % order.len();
+                }
+                stream.write_all(&buffer).await?;
+            }
+        }
+    }
+
+    stream.flush().await?;
+    stream.shutdown().await?;
+    info!("Bye 👋");
+    Ok(())
+}
+
+fn write_pixel(buffer: &mut Vec<u8>, i: usize, size: (u32, u32), offset: (u32, u32), pixel: Pixel) {
+    if let Pixel::Rgb(r, g, b) = pixel {
+        let i = i as u32;
+        let x = i % size.0 + offset.0;
+        let y = i / size.0 + offset.1;
+        write_instruction(buffer, x, y, (r, g, b)).unwrap();
+    }
+}
+
+/// Decodes frames until the stream ends and publishes the latest one
+fn read_frames<R: BufRead>(
+    mut reader: R,
+    format: LiveFormat,
+    sender: &watch::Sender<Option<Arc<LiveFrame>>>,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        LiveFormat::Rgb24(width, height) => {
+            let mut data = vec![0; (width * height * 3) as usize];
+            while read_frame(&mut reader, &mut data)? {
+                let image = data
+                    .chunks_exact(3)
+                    .map(|rgb| Pixel::Rgb(rgb[0], rgb[1], rgb[2]))
+                    .collect();
+                let frame = LiveFrame {
+                    size: (width, height),
+                    image,
+                };
+                if sender.send(Some(Arc::new(frame))).is_err() {
+                    break;
+                }
+            }
+        }
+        LiveFormat::Y4m => {
+            let header = Y4mHeader::read(&mut reader)?;
+            let mut data = vec![0; header.frame_len()];
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+                if !line.starts_with(b"FRAME") {
+                    return Err("Invalid Y4M frame header".into());
+                }
+                if !read_frame(&mut reader, &mut data)? {
+                    break;
+                }
+                let frame = LiveFrame {
+                    size: header.size,
+                    image: header.to_rgb(&data),
+                };
+                if sender.send(Some(Arc::new(frame))).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fills the buffer with the next frame, false if the stream ended
+fn read_frame<R: Read>(reader: &mut R, data: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(data) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+struct Y4mHeader {
+    size: (u32, u32),
+    /// Horizontal and vertical chroma subsampling, none for monochrome
+    subsampling: Option<(u32, u32)>,
+}
+
+impl Y4mHeader {
+    fn read<R: BufRead>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut params = line.trim_end().split(' ');
+        if params.next() != Some("YUV4MPEG2") {
+            return Err("The stream is not in the YUV4MPEG2 format".into());
+        }
+        let (mut width, mut height) = (0, 0);
+        let mut subsampling = Some((2, 2));
+        for param in params.filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_at(1);
+            match key {
+                "W" => width = value.parse()?,
+                "H" => height = value.parse()?,
+                "C" => match value {
+                    "420" | "420jpeg" | "420paldv" | "420mpeg2" => subsampling = Some((2, 2)),
+                    "422" => subsampling = Some((2, 1)),
+                    "444" => subsampling = Some((1, 1)),
+                    "mono" => subsampling = None,
+                    _ => return Err(format!("Unsupported Y4M colour space {}", value).into()),
+                },
+                _ => {}
+            }
+        }
+        if width == 0 || height == 0 {
+            return Err("The Y4M header has no frame size".into());
+        }
+        Ok(Y4mHeader {
+            size: (width, height),
+            subsampling,
+        })
+    }
+
+    fn chroma_size(&self) -> (u32, u32) {
+        match self.subsampling {
+            Some((sx, sy)) => (self.size.0.div_ceil(sx), self.size.1.div_ceil(sy)),
+            None => (0, 0),
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        let chroma = self.chroma_size();
+        (self.size.0 * self.size.1 + 2 * chroma.0 * chroma.1) as usize
+    }
+
+    /// Converts a frame of limited range BT.601 YUV to RGB
+    fn to_rgb(&self, data: &[u8]) -> Vec<Pixel> {
+        let (width, height) = self.size;
+        let luma_len = (width * height) as usize;
+        let chroma = self.chroma_size();
+        let chroma_len = (chroma.0 * chroma.1) as usize;
+        let (u_plane, v_plane) = data[luma_len..].split_at(chroma_len);
+        (0..luma_len)
+            .map(|i| {
+                let (x, y) = (i as u32 % width, i as u32 / width);
+                let luma = 1.164 * (data[i] as f32 - 16.0);
+                let (u, v) = match self.subsampling {
+                    Some((sx, sy)) => {
+                        let ci = (x / sx + chroma.0 * (y / sy)) as usize;
+                        (u_plane[ci] as f32 - 128.0, v_plane[ci] as f32 - 128.0)
+                    }
+                    None => (0.0, 0.0),
+                };
+                let channel = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+                Pixel::Rgb(
+                    channel(luma + 1.596 * v),
+                    channel(luma - 0.392 * u - 0.813 * v),
+                    channel(luma + 2.017 * u),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes the stream and returns the last frame published
+    fn last_frame(stream: &[u8], format: LiveFormat) -> Option<Arc<LiveFrame>> {
+        let (sender, receiver) = watch::channel(None);
+        read_frames(stream, format, &sender).unwrap();
+        let frame = receiver.borrow().clone();
+        frame
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("y4m".parse(), Ok(LiveFormat::Y4m));
+        assert_eq!("rgb24:640x480".parse(), Ok(LiveFormat::Rgb24(640, 480)));
+    }
+
+    #[test]
+    fn parse_invalid_format() {
+        for format in &[
+            "",
+            "Y4M",
+            "rgb24",
+            "rgb24:",
+            "rgb24:640",
+            "rgb24:640x",
+            "rgb24:x480",
+            "rgb24:0x0",
+            "rgb24:0x480",
+            "rgb24:640x0",
+            "rgb24:-1x480",
+            "rgb:640x480",
+        ] {
+            assert!(format.parse::<LiveFormat>().is_err(), "{}", format);
+        }
+    }
+
+    #[test]
+    fn rgb24_frames() {
+        let stream = [
+            1, 2, 3, 4, 5, 6, // first frame
+            7, 8, 9, 10, 11, 12, // second frame
+            13, 14, 15, // incomplete frame
+        ];
+        let frame = last_frame(&stream, LiveFormat::Rgb24(2, 1)).unwrap();
+        assert_eq!(frame.size, (2, 1));
+        assert_eq!(
+            frame.image,
+            vec![Pixel::Rgb(7, 8, 9), Pixel::Rgb(10, 11, 12)]
+        );
+    }
+
+    #[test]
+    fn rgb24_without_frame() {
+        assert!(last_frame(&[1, 2, 3], LiveFormat::Rgb24(2, 1)).is_none());
+    }
+
+    #[test]
+    fn y4m_header() {
+        let header = Y4mHeader::read(&mut &b"YUV4MPEG2 W640 H480 F25:1 Ip A1:1\n"[..]).unwrap();
+        assert_eq!(header.size, (640, 480));
+        assert_eq!(header.subsampling, Some((2, 2)));
+        assert_eq!(header.frame_len(), 640 * 480 * 3 / 2);
+
+        for (tag, subsampling) in &[
+            ("420", Some((2, 2))),
+            ("420jpeg", Some((2, 2))),
+            ("420paldv", Some((2, 2))),
+            ("420mpeg2", Some((2, 2))),
+            ("422", Some((2, 1))),
+            ("444", Some((1, 1))),
+            ("mono", None),
+        ] {
+            let line = format!("YUV4MPEG2 W3 H3 C{}\n", tag);
+            let header = Y4mHeader::read(&mut line.as_bytes()).unwrap();
+            assert_eq!(header.subsampling, *subsampling, "{}", tag);
+        }
+        let header = Y4mHeader::read(&mut &b"YUV4MPEG2 W3 H3 C420\n"[..]).unwrap();
+        assert_eq!(header.chroma_size(), (2, 2));
+        assert_eq!(header.frame_len(), 9 + 2 * 4);
+    }
+
+    #[test]
+    fn y4m_invalid_header() {
+        for line in &[
+            "",
+            "YUV4MPEG W2 H2\n",
+            "YUV4MPEG2 H2\n",
+            "YUV4MPEG2 W2\n",
+            "YUV4MPEG2 W0 H2\n",
+            "YUV4MPEG2 Wx H2\n",
+            "YUV4MPEG2 W2 H2 C420p10\n",
+            "YUV4MPEG2 W2 H2 C444alpha\n",
+            "YUV4MPEG2 W2 H2 C422p12\n",
+            "YUV4MPEG2 W2 H2 Cmonochrome\n",
+        ] {
+            assert!(Y4mHeader::read(&mut line.as_bytes()).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn y4m_420_upsampling() {
+        let mut stream = b"YUV4MPEG2 W4 H2 C420jpeg\nFRAME\n".to_vec();
+        // Grey luma, the chroma of the right half is red
+        stream.extend_from_slice(&[126; 8]);
+        stream.extend_from_slice(&[128, 128]);
+        stream.extend_from_slice(&[128, 255]);
+        let frame = last_frame(&stream, LiveFormat::Y4m).unwrap();
+        assert_eq!(frame.size, (4, 2));
+        let grey = Pixel::Rgb(128, 128, 128);
+        let red = Pixel::Rgb(255, 25, 128);
+        assert_eq!(
+            frame.image,
+            vec![grey, grey, red, red, grey, grey, red, red]
+        );
+    }
+
+    #[test]
+    fn y4m_444_frames() {
+        let mut stream = b"YUV4MPEG2 W2 H1 C444\nFRAME\n".to_vec();
+        // Black
+        stream.extend_from_slice(&[16, 16, 128, 128, 128, 128]);
+        stream.extend_from_slice(b"FRAME Ixyz\n");
+        // Grey and blue, every pixel has its own chroma
+        stream.extend_from_slice(&[126, 126, 128, 255, 128, 128]);
+        let frame = last_frame(&stream, LiveFormat::Y4m).unwrap();
+        assert_eq!(
+            frame.image,
+            vec![Pixel::Rgb(128, 128, 128), Pixel::Rgb(128, 78, 255)]
+        );
+
+        // An incomplete frame is dropped
+        stream.truncate(stream.len() - 1);
+        let frame = last_frame(&stream, LiveFormat::Y4m).unwrap();
+        assert_eq!(frame.image, vec![Pixel::Rgb(0, 0, 0), Pixel::Rgb(0, 0, 0)]);
+    }
+
+    #[test]
+    fn y4m_mono() {
+        let stream = b"YUV4MPEG2 W2 H1 Cmono\nFRAME\n\x10\xeb";
+        let frame = last_frame(stream, LiveFormat::Y4m).unwrap();
+        assert_eq!(
+            frame.image,
+            vec![Pixel::Rgb(0, 0, 0), Pixel::Rgb(255, 255, 255)]
+        );
+    }
+
+    #[test]
+    fn y4m_invalid_frame_header() {
+        let (sender, _receiver) = watch::channel(None);
+        let stream = b"YUV4MPEG2 W1 H1 C444\nFRAME\n\x10\x80\x80JUNK\n\x10\x80\x80";
+        assert!(read_frames(&stream[..], LiveFormat::Y4m, &sender).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    io::{self, Write},
+    str::FromStr,
+};
+
+/// How log records are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The message, prefixed with the level unless it is informational
+    Text,
+    /// One JSON object per line with the time, level, module and message
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    /// Parses `text` or `json`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+struct Logger {
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Libraries only get to report problems
+        metadata.level() <= Level::Warn || metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Text if record.level() == Level::Info => format!("{}", record.args()),
+            LogFormat::Text => format!("{} {}", record.level(), record.args()),
+            LogFormat::Json => serde_json::json!({
+                "time": chrono::Local::now().to_rfc3339(),
+                "level": record.level().as_str().to_lowercase(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+        };
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger for the whole process
+pub fn init(level: LevelFilter, format: LogFormat) {
+    if log::set_boxed_logger(Box::new(Logger { format })).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -1,138 +1,509 @@
-use std::{borrow::Borrow, error::Error, fs::File, sync::Arc, time::Duration};
-
-use futures::{lock::Mutex, TryStreamExt};
-use hyper::{
-    body::{Bytes, HttpBody},
-    Client,
+use std::{
+    error::Error,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
+
+use futures::{future::join_all, lock::Mutex, FutureExt};
+use log::{debug, error, info, trace, warn};
 use rand::thread_rng;
-use tokio::{io::AsyncWriteExt, runtime::Runtime, signal};
-use tokio::{net::TcpStream, sync::oneshot, time::sleep};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{oneshot, watch};
+use tokio::time::{sleep, Instant};
+use tokio::{runtime::Runtime, signal, task, time::timeout};
 
-use image_data::{FlutInstructions, GifSource};
+use config::{Job, TileArea};
+use control::{Control, JobHandle};
+use image_data::{FlutInstructions, Frame, Tiling};
+use limit::RateLimit;
+use live::LiveOptions;
+use net::Stream;
+use source::Source;
+use stats::Stats;
 
 mod cli;
+mod color;
+mod compose;
+mod config;
+mod control;
+mod generator;
 mod image_data;
+mod image_file;
+mod limit;
+mod live;
+mod logging;
+mod metrics;
+mod motion;
+mod net;
+mod playlist;
+mod proxy;
+mod reload;
+mod source;
+mod stats;
+mod text;
+mod transform;
+mod udp;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+/// Longest pause between attempts to reconnect
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Size of the pieces commands are written in when the rate is limited
+const CHUNK_SIZE: usize = 16 * 1024;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let options = cli::get_options();
-    println!("🖼️ File: {}", options.file);
-    println!("🖥️ URL: {}", options.url);
+    logging::init(options.log_level, options.log_format);
+    net::init(options.net.clone());
+    let jobs = match &options.config {
+        Some(path) => {
+            info!("📋 Config: {}", path);
+            config::load_jobs(path)?
+        }
+        None => vec![options.job.clone().unwrap()],
+    };
+    for job in &jobs {
+        match &job.source {
+            Source::Gif(file) => info!("🖼️ File: {}", file),
+            Source::Text(text) => info!("🔤 Text: {}", text.text),
+            Source::Generator(options) => info!("🎨 Generator: {:?}", options.generator),
+            Source::FramesDir { path, fps } => info!("🎞️ Frames: {} at {} fps", path, fps),
+            Source::Still(file) => info!("🖼️ Image: {}", file),
+            Source::Layers(layers) => info!("🥞 Layers: {}", layers.len()),
+            Source::Playlist(path) => info!("📼 Playlist: {}", path),
+        }
+        info!("🖥️ URL: {}", job.url);
+    }
 
     // Create Tokio Runtime
     let rt = Runtime::new().unwrap();
 
-    let file_path = options.file.clone();
-    let gif = match &file_path {
-        n if n.starts_with("http:") || n.starts_with("https:") => rt.block_on(async move {
-            let https = hyper_tls::HttpsConnector::new();
-            let http_client = Client::builder().build::<_, hyper::Body>(https);
-            let res = http_client.get(file_path.parse()?).await?;
-            let gif_data = res.into_body();
-            let size_hint = gif_data.size_hint();
-
-            let mut data = size_hint
-                .exact()
-                .or_else(|| size_hint.upper())
-                .map(|size| size as usize)
-                .map_or_else(Vec::new, Vec::with_capacity);
-            let bytes_vec: Vec<Bytes> = gif_data.try_collect().await?;
-            for bytes in bytes_vec {
-                data.extend(bytes.into_iter());
-            }
+    if let Some(format) = options.live {
+        let job = &jobs[0];
+        let input = match &job.source {
+            Source::Gif(input) => input,
+            _ => return Err("--live reads the stream from --file".into()),
+        };
+        let live_options = LiveOptions {
+            format,
+            offset: job.offset,
+            similarity: job.similarity,
+            shuffle: job.shuffle,
+        };
+        return rt.block_on(live::fluten_live(&job.url, input, live_options));
+    }
 
-            println!("🔽 Downloaded file");
+    let commands = jobs
+        .iter()
+        .map(|job| prepare(job, &rt))
+        .collect::<Result<Vec<_>, _>>()?;
 
-            Result::<_, Box<dyn Error>>::Ok(GifSource::Vec(data))
-        })?,
-        _ => GifSource::File(File::open(file_path)?),
-    };
+    let (job_handles, receivers): (Vec<_>, Vec<_>) = jobs
+        .iter()
+        .zip(commands)
+        .map(|(job, commands)| JobHandle::new(job.clone(), commands))
+        .unzip();
+    let job_handles = Arc::new(job_handles);
+    if let Some(addr) = options.control {
+        let job_handles = job_handles.clone();
+        rt.spawn(async move {
+            if let Err(err) = control::serve(addr, job_handles).await {
+                error!("❌ Control API: {}", err);
+            }
+        });
+    }
 
-    println!("🖼️ Parsing image...");
+    if options.watch {
+        rt.spawn(reload::watch_sources(job_handles.clone()));
+        rt.spawn(reload::reload_on_hangup(job_handles.clone()));
+    }
 
-    let image = match gif {
-        GifSource::File(file) => image_data::load_image(file),
-        GifSource::Vec(vec) => image_data::load_image::<&[u8]>(vec.borrow()),
-    };
+    if let Some(interval) = options.stats {
+        rt.spawn(stats::report(job_handles.clone(), interval));
+    }
+    if let Some(interval) = options.read_back {
+        for id in 0..job_handles.len() {
+            rt.spawn(metrics::read_back(job_handles.clone(), id, interval));
+        }
+    }
 
-    println!("✅ Optimizing...");
+    // The limits apply to all connections together
+    let limit = Arc::new(RateLimit::new(options.max_bandwidth, options.max_pps));
 
-    let optimized = image_data::optimize_image(image, options.similarity);
+    // All jobs share the runtime and keep running if one of them fails
+    let handles: Vec<_> = jobs
+        .iter()
+        .zip(receivers)
+        .zip(job_handles.iter())
+        .map(|((job, control), handle)| {
+            let url = job.url.clone();
+            let stats = handle.stats.clone();
+            let limit = limit.clone();
+            rt.spawn(async move {
+                fluten(&url, control, stats, limit).await.map_err(|err| {
+                    error!("❌ {}: {}", url, err);
+                    err.to_string()
+                })
+            })
+        })
+        .collect();
+    for result in rt.block_on(join_all(handles)) {
+        result??;
+    }
+    Ok(())
+}
 
-    println!("📝 Generating Commands...");
+/// Loads the source of a job and pre-generates all commands for flooding it
+fn prepare(job: &Job, rt: &Runtime) -> Result<FlutInstructions, Box<dyn Error>> {
+    if let Source::Playlist(path) = &job.source {
+        return playlist::prepare(job, path, rt);
+    }
+    let image = load_frames(&job.source, job, rt)?;
 
-    let commands = image_data::optimized_image_to_instructions(
+    let started = Instant::now();
+    let (optimized, tiling) = match &job.motion {
+        Some(motion) => {
+            let canvas = canvas_size(job, rt)?;
+            info!("🏓 Moving over {}x{} canvas...", canvas.0, canvas.1);
+            let optimized = motion::animate(image, job.offset, canvas, motion, job.similarity);
+            (optimized, Tiling::single((0, 0)))
+        }
+        None => {
+            info!("✅ Optimizing...");
+            let optimized = image_data::optimize_image(image, job.similarity);
+            let tiling = tiling(job, extent(&optimized.frames), rt)?;
+            (optimized, tiling)
+        }
+    };
+    debug!(
+        "Optimized {} frames in {:?}",
+        optimized.corrections.len(),
+        started.elapsed()
+    );
+
+    info!("📝 Generating Commands...");
+    let started = Instant::now();
+    let mut commands = image_data::optimized_image_to_instructions(
         optimized,
-        options.offset.0,
-        options.offset.1,
-        &mut if options.shuffle {
+        &tiling,
+        &mut if job.shuffle {
             Some(thread_rng())
         } else {
             None
         }
         .as_mut(),
     );
+    debug!(
+        "Generated {} bytes of commands in {:?}",
+        commands.start.len()
+            + commands
+                .frames
+                .iter()
+                .map(|(cmds, corrections, _)| {
+                    cmds.len() + corrections.iter().map(|c| c.len()).sum::<usize>()
+                })
+                .sum::<usize>(),
+        started.elapsed()
+    );
+    // Moving images have frames of their own
+    if job.motion.is_none() {
+        commands.epoch = job.source.epoch();
+    }
+    Ok(commands)
+}
 
-    rt.block_on(fluten(&options.url, commands, options.time_factor as u64))?;
-    Ok(())
+/// Loads a source and applies the chroma key, transforms, filters and scale of the job
+fn load_frames(source: &Source, job: &Job, rt: &Runtime) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let started = Instant::now();
+    let mut image = source.load(rt)?;
+    debug!("Loaded {} frames in {:?}", image.len(), started.elapsed());
+    if let Some(key) = &job.key {
+        color::key_out(&mut image, key);
+    }
+    if !job.transforms.is_empty() {
+        info!("📐 Transforming...");
+        image = transform::apply(image, &job.transforms);
+    }
+    if !job.filters.is_empty() {
+        info!("🎨 Filtering colours...");
+        color::apply(&mut image, &job.filters);
+    }
+    if job.scale > 1 {
+        image = image.iter().map(|frame| frame.scale(job.scale)).collect();
+    }
+    Ok(image)
 }
 
+/// Positions at which frames of the given extent are drawn for a job
+fn tiling(job: &Job, tile: (u32, u32), rt: &Runtime) -> Result<Tiling, Box<dyn Error>> {
+    let area = match job.tile {
+        Some(TileArea::Size(width, height)) => (width, height),
+        Some(TileArea::Canvas) => {
+            let canvas = canvas_size(job, rt)?;
+            (
+                canvas.0.saturating_sub(job.offset.0),
+                canvas.1.saturating_sub(job.offset.1),
+            )
+        }
+        None => return Ok(Tiling::single(job.offset)),
+    };
+    let tiling = Tiling::grid(job.offset, tile, area);
+    info!(
+        "🧱 Tiling {}x{} with {} copies...",
+        area.0,
+        area.1,
+        tiling.offsets.len()
+    );
+    Ok(tiling)
+}
+
+/// Bottom right corner of the area covered by the frames
+fn extent(frames: &[Frame]) -> (u32, u32) {
+    frames.iter().fold((0, 0), |size, frame| {
+        let end = frame.end();
+        (size.0.max(end.0), size.1.max(end.1))
+    })
+}
+
+fn canvas_size(job: &Job, rt: &Runtime) -> Result<(u32, u32), Box<dyn Error>> {
+    match job.canvas {
+        Some(canvas) => Ok(canvas),
+        None => rt.block_on(query_size(&job.url)),
+    }
+}
+
+/// Asks the server for the size of its canvas
+async fn query_size(url: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    if url.starts_with("udp://") {
+        return Err("udp:// needs --canvas, UDP servers don't answer SIZE".into());
+    }
+    info!("📏 Querying canvas size...");
+    let mut stream = BufReader::new(net::connect(url).await?);
+    stream.get_mut().write_all(b"SIZE\n").await?;
+    stream.get_mut().flush().await?;
+    let mut line = String::new();
+    timeout(Duration::from_secs(5), stream.read_line(&mut line)).await??;
+    debug!("Canvas size reply: {}", line.trim());
+    let mut reply = line.split_whitespace();
+    match (reply.next(), reply.next(), reply.next()) {
+        (Some("SIZE"), Some(width), Some(height)) => Ok((width.parse()?, height.parse()?)),
+        _ => Err(format!("Unexpected reply to SIZE: {}", line.trim()).into()),
+    }
+}
+
+/// Floods the commands of a job and reconnects when the connection is lost
 async fn fluten(
     url: &str,
-    commands: FlutInstructions,
-    time_factor: u64,
+    mut control: watch::Receiver<Control>,
+    stats: Arc<Stats>,
+    limit: Arc<RateLimit>,
 ) -> Result<(), Box<dyn Error>> {
-    println!("📡 Connecting to server...");
-    let mut stream = TcpStream::connect(url).await?;
-    println!("🌊🌊 Flut! 🌊🌊");
-    stream.write_all(&commands.start).await?;
-    stream.flush().await?;
     let stop = Arc::new(Mutex::new(false));
     let stop2 = stop.clone();
     tokio::spawn(async move {
         drop(signal::ctrl_c().await);
-        println!("🧽 Stopping the Flut...");
+        info!("🧽 Stopping the Flut...");
         *stop2.lock().await = true;
     });
 
+    info!("📡 Connecting to server...");
+    let mut stream = net::connect_job(url)
+        .await
+        .inspect_err(|_| stats.connection_failed())?;
     loop {
-        for (cmds, corrections, delay) in commands.frames.iter() {
-            let (send_done, mut done) = oneshot::channel();
-            let delay2 = *delay;
-            tokio::spawn(async move {
-                sleep(Duration::from_millis(delay2 as u64 * 10 * time_factor)).await;
-                drop(send_done.send(()));
-            });
-            /*loop {
-                stream.write_all(cmds).await?;
-                if *done.lock().await {
-                    break;
-                }
-            }*/
-            stream.write_all(cmds).await?;
-            stream.flush().await?;
-            if corrections.len() > 0 {
-                let mut i = 0;
-                loop {
-                    match done.try_recv() {
-                        Ok(()) => break,
-                        Err(oneshot::error::TryRecvError::Closed) => break,
-                        Err(oneshot::error::TryRecvError::Empty) => {
-                            stream.write_all(&corrections[i]).await?;
-                            i = (i + 1) % corrections.len();
+        info!("🌊🌊 Flut! 🌊🌊");
+        match flood(&mut stream, &mut control, &stats, &limit, &stop).await {
+            Ok(()) => {
+                info!("Bye 👋");
+                return Ok(());
+            }
+            Err(err) => {
+                stats.connection_failed();
+                warn!("🔌 {}: {}", url, err);
+            }
+        }
+        stream = match reconnect(url, &stats, &stop).await {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        stats.reconnected();
+    }
+}
+
+/// Connects again with growing pauses until it succeeds or the flut is stopped
+async fn reconnect(url: &str, stats: &Stats, stop: &Mutex<bool>) -> Option<Stream> {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        info!("📡 Reconnecting in {}s...", backoff.as_secs());
+        let retry = Instant::now() + backoff;
+        while Instant::now() < retry {
+            if *stop.lock().await {
+                return None;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        match net::connect_job(url).await {
+            Ok(stream) => return Some(stream),
+            Err(err) => {
+                stats.connection_failed();
+                error!("❌ {}: {}", url, err);
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Floods the commands over one connection until it is stopped or fails,
+/// changes to the settings apply at the next frame
+async fn flood(
+    stream: &mut Stream,
+    control: &mut watch::Receiver<Control>,
+    stats: &Stats,
+    limit: &RateLimit,
+    stop: &Mutex<bool>,
+) -> Result<(), Box<dyn Error>> {
+    if let Ok((local, peer)) = stream.addrs() {
+        debug!("Connected from {} to {}", local, peer);
+    }
+    let mut settings = control.borrow().clone();
+    let mut pixels = frame_pixels(&settings.commands);
+    write_limited(stream, &settings.commands.start, limit).await?;
+    stream.flush().await?;
+    stats.sent(
+        settings.commands.start.len(),
+        stats::count_pixels(&settings.commands.start),
+    );
+
+    let mut frame = 0;
+    let mut repeats = 0;
+    loop {
+        let commands = settings.commands.clone();
+        // Frames that show the time skip to the one that is due, the
+        // corrections repair what the skipped ones would have drawn
+        let due = commands.due_at(SystemTime::now(), settings.time_factor);
+        if let Some((position, _)) = due {
+            frame = position;
+        }
+        let index = commands.sequence[frame];
+        let (cmds, corrections, _) = &commands.frames[index];
+        stats.set_frame(index);
+        frame = commands.next_position(frame, &mut repeats, settings.time_factor);
+        let started = Instant::now();
+        let due = due.map_or_else(
+            || commands.delay(index, settings.time_factor),
+            |(_, left)| left,
+        );
+        let (send_done, mut done) = oneshot::channel();
+        tokio::spawn(async move {
+            sleep(due).await;
+            let _ = send_done.send(());
+        });
+        write_limited(stream, cmds, limit).await?;
+        stream.flush().await?;
+        stats.sent(cmds.len(), pixels[index]);
+        let mut written = 0;
+        if !corrections.is_empty() {
+            let mut i = 0;
+            let mut bytes = 0;
+            loop {
+                match done.try_recv() {
+                    Ok(()) => break,
+                    Err(oneshot::error::TryRecvError::Closed) => break,
+                    Err(oneshot::error::TryRecvError::Empty) => {
+                        // Corrections only use the budget left by frame changes
+                        if let Err(wait) = limit.try_acquire(corrections[i].len(), 1) {
+                            if timeout(wait, &mut done).await.is_ok() {
+                                break;
+                            }
+                            continue;
+                        }
+                        stream.write_all(&corrections[i]).await?;
+                        bytes += corrections[i].len();
+                        written += 1;
+                        i = (i + 1) % corrections.len();
+                        // Let other jobs and the frame timer run
+                        if written % 256 == 0 {
+                            stats.sent(bytes, 256);
+                            bytes = 0;
+                            // tokio 1.3 marks the unit result of yield_now as must_use
+                            let _ = task::yield_now().await;
                         }
                     }
                 }
-            } else {
-                done.await?;
             }
+            stats.sent(bytes, written % 256);
+        } else {
+            done.await?;
+        }
+        let rounds = written as f64 / corrections.len().max(1) as f64;
+        let lag = started.elapsed().saturating_sub(due);
+        stats.frame_played(rounds, lag);
+        trace!(
+            "Frame {} took {:?}, {} corrections in {:.1} rounds, {:?} late",
+            index,
+            started.elapsed(),
+            written,
+            rounds,
+            lag
+        );
 
-            if *stop.lock().await {
-                stream.flush().await?;
-                stream.shutdown().await?;
-                println!("Bye 👋");
-                return Ok(());
+        // Pick up changes at the frame boundary and wait while paused
+        loop {
+            let changed = if settings.paused {
+                timeout(Duration::from_millis(100), control.changed())
+                    .await
+                    .ok()
+            } else {
+                control.changed().now_or_never()
+            };
+            if let Some(Ok(())) = changed {
+                let new = control.borrow().clone();
+                if !Arc::ptr_eq(&new.commands, &settings.commands) {
+                    write_limited(stream, &new.commands.start, limit).await?;
+                    stats.sent(
+                        new.commands.start.len(),
+                        stats::count_pixels(&new.commands.start),
+                    );
+                    pixels = frame_pixels(&new.commands);
+                    frame = 0;
+                    repeats = 0;
+                }
+                settings = new;
             }
+            if !settings.paused || *stop.lock().await {
+                break;
+            }
+        }
+
+        if *stop.lock().await {
+            stream.flush().await?;
+            stream.shutdown().await?;
+            return Ok(());
         }
     }
 }
+
+/// Writes commands that have to be sent, in chunks at the limited rate
+async fn write_limited(
+    stream: &mut Stream,
+    commands: &[u8],
+    limit: &RateLimit,
+) -> std::io::Result<()> {
+    if !limit.is_limited() {
+        return stream.write_all(commands).await;
+    }
+    for chunk in commands.chunks(CHUNK_SIZE) {
+        limit.acquire(chunk.len(), stats::count_pixels(chunk)).await;
+        stream.write_all(chunk).await?;
+    }
+    Ok(())
+}
+
+/// Number of pixel commands of each frame
+fn frame_pixels(commands: &FlutInstructions) -> Vec<u64> {
+    commands
+        .frames
+        .iter()
+        .map(|(cmds, _, _)| stats::count_pixels(cmds))
+        .collect()
+}
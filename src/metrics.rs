@@ -0,0 +1,267 @@
+use log::{error, warn};
+use rand::seq::SliceRandom;
+use std::{error::Error, fmt::Write as _, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    time::{sleep, timeout},
+};
+
+use crate::control::JobHandle;
+use crate::image_data::Pixel;
+use crate::net;
+use crate::stats::Snapshot;
+
+/// Pixels compared in every read-back
+const SAMPLES: usize = 256;
+
+/// Counters and gauges of all jobs in the Prometheus text format
+pub fn render(jobs: &[JobHandle]) -> String {
+    let snapshots: Vec<(String, Snapshot)> = jobs
+        .iter()
+        .enumerate()
+        .map(|(id, job)| {
+            let url = job.job().url.replace('\\', "\\\\").replace('"', "\\\"");
+            (
+                format!("job=\"{}\",url=\"{}\"", id, url),
+                job.stats.snapshot(),
+            )
+        })
+        .collect();
+    let controls: Vec<_> = jobs.iter().map(|job| job.control()).collect();
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(usize) -> Option<f64>| {
+        let _ = writeln!(out, "# HELP pixelflut_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE pixelflut_{} {}", name, kind);
+        for (i, (labels, _)) in snapshots.iter().enumerate() {
+            if let Some(value) = value(i) {
+                let _ = writeln!(out, "pixelflut_{}{{{}}} {}", name, labels, value);
+            }
+        }
+    };
+    let stats = |i: usize| &snapshots[i].1;
+    metric(
+        "bytes_written_total",
+        "counter",
+        "Bytes sent to the server",
+        &|i| Some(stats(i).bytes as f64),
+    );
+    metric(
+        "pixels_written_total",
+        "counter",
+        "Pixel commands sent to the server",
+        &|i| Some(stats(i).pixels as f64),
+    );
+    metric("frames_played_total", "counter", "Frames played", &|i| {
+        Some(stats(i).frames as f64)
+    });
+    metric(
+        "correction_rounds_total",
+        "counter",
+        "Times the pixels of the frames were redrawn before the frames ended",
+        &|i| Some(stats(i).correction_rounds),
+    );
+    metric(
+        "frame_lag_seconds_total",
+        "counter",
+        "Time by which frames ended late",
+        &|i| Some(stats(i).lag.as_secs_f64()),
+    );
+    metric(
+        "reconnects_total",
+        "counter",
+        "Connections reopened",
+        &|i| Some(stats(i).reconnects as f64),
+    );
+    metric(
+        "connection_errors_total",
+        "counter",
+        "Failed connection attempts and lost connections",
+        &|i| Some(stats(i).connection_errors as f64),
+    );
+    metric(
+        "read_back_pixels_total",
+        "counter",
+        "Pixels read back from the server",
+        &|i| Some(stats(i).sampled as f64),
+    );
+    metric(
+        "griefed_pixels_total",
+        "counter",
+        "Pixels read back with a different colour than drawn",
+        &|i| Some(stats(i).griefed as f64),
+    );
+    metric(
+        "grief_ratio",
+        "gauge",
+        "Share of griefed pixels in the last read-back",
+        &|i| stats(i).grief_ratio,
+    );
+    metric(
+        "time_factor",
+        "gauge",
+        "Factor by which the frame delays are scaled",
+        &|i| Some(controls[i].time_factor as f64),
+    );
+    metric("paused", "gauge", "Whether the job is paused", &|i| {
+        Some(controls[i].paused as u8 as f64)
+    });
+    out
+}
+
+/// Periodically reads pixels of the frame that is being played back from
+/// the server to measure how much of it is griefed
+pub async fn read_back(jobs: Arc<Vec<JobHandle>>, id: usize, interval: Duration) {
+    let job = &jobs[id];
+    let url = job.job().url;
+    // Datagrams are sent without waiting for replies
+    if url.starts_with("udp://") {
+        warn!(
+            "⚠️ Skipping the read-back from {}, UDP servers don't answer",
+            url
+        );
+        return;
+    }
+    loop {
+        sleep(interval).await;
+        if job.control().paused {
+            continue;
+        }
+        if let Err(err) = sample(&url, job).await {
+            error!("❌ Read-back from {}: {}", url, err);
+        }
+    }
+}
+
+async fn sample(url: &str, job: &JobHandle) -> Result<(), Box<dyn Error>> {
+    let commands = job.control().commands;
+    let frame = job.stats.frame();
+    let expected: Vec<(u32, u32, Pixel)> = match commands.frames.get(frame) {
+        Some((_, corrections, _)) => corrections
+            .choose_multiple(&mut rand::thread_rng(), SAMPLES)
+            .filter_map(|command| parse_pixel(command))
+            .collect(),
+        None => return Ok(()),
+    };
+    if expected.is_empty() {
+        return Ok(());
+    }
+
+    let mut stream = BufReader::new(net::connect(url).await?);
+    let mut request = String::new();
+    for (x, y, _) in &expected {
+        let _ = writeln!(request, "PX {} {}", x, y);
+    }
+    stream.get_mut().write_all(request.as_bytes()).await?;
+    stream.get_mut().flush().await?;
+    let mut griefed = 0;
+    let mut line = String::new();
+    timeout(Duration::from_secs(5), async {
+        // Replies arrive in the order of the requests
+        for &pixel in &expected {
+            line.clear();
+            if stream.read_line(&mut line).await? == 0 {
+                return Err("The server closed the connection".into());
+            }
+            if parse_pixel(line.as_bytes()) != Some(pixel) {
+                griefed += 1;
+            }
+        }
+        Ok::<_, Box<dyn Error>>(())
+    })
+    .await
+    .map_err(|_| "The server did not answer in time")??;
+
+    // The frame may have ended or the commands may have been swapped while
+    // sampling, then colours are expected to differ
+    if job.stats.frame() == frame && Arc::ptr_eq(&commands, &job.control().commands) {
+        job.stats.read_back(expected.len() as u64, griefed);
+    }
+    Ok(())
+}
+
+/// Parses `PX <X> <Y> <RRGGBB>`, ignoring an alpha channel
+fn parse_pixel(command: &[u8]) -> Option<(u32, u32, Pixel)> {
+    let command = std::str::from_utf8(command).ok()?;
+    let mut parts = command.split_whitespace();
+    if parts.next()? != "PX" {
+        return None;
+    }
+    let x = parts.next()?.parse().ok()?;
+    let y = parts.next()?.parse().ok()?;
+    let (r, g, b) = Pixel::hex_to_rgb(parts.next()?.get(..6)?)?;
+    Some((x, y, Pixel::Rgb(r, g, b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Job;
+    use crate::image_data::FlutInstructions;
+    use crate::source::Source;
+
+    fn job(url: &str) -> JobHandle {
+        let job = Job {
+            source: Source::Still("image.png".into()),
+            url: url.into(),
+            offset: (0, 0),
+            key: None,
+            transforms: Vec::new(),
+            filters: Vec::new(),
+            scale: 1,
+            similarity: 0,
+            shuffle: false,
+            time_factor: 10,
+            motion: None,
+            canvas: None,
+            tile: None,
+        };
+        let commands = FlutInstructions {
+            start: Vec::new(),
+            frames: Vec::new(),
+            sequence: Vec::new(),
+            fixed_delays: Vec::new(),
+            timed_loops: Vec::new(),
+            epoch: None,
+        };
+        JobHandle::new(job, commands).0
+    }
+
+    #[test]
+    fn render_exposition_format() {
+        let jobs = vec![job("tcp://a:1337"), job(r#"tcp://"b"\c"#)];
+        jobs[0].stats.sent(180, 10);
+        jobs[1].stats.read_back(4, 1);
+
+        let out = render(&jobs);
+        let bytes = "# HELP pixelflut_bytes_written_total Bytes sent to the server\n\
+            # TYPE pixelflut_bytes_written_total counter\n\
+            pixelflut_bytes_written_total{job=\"0\",url=\"tcp://a:1337\"} 180\n\
+            pixelflut_bytes_written_total{job=\"1\",url=\"tcp://\\\"b\\\"\\\\c\"} 0\n";
+        assert!(out.starts_with(bytes), "{}", out);
+        assert!(out.contains("pixelflut_time_factor{job=\"0\",url=\"tcp://a:1337\"} 10\n"));
+
+        // Gauges without a value are left out
+        assert!(!out.contains("pixelflut_grief_ratio{job=\"0\""));
+        assert!(
+            out.contains("pixelflut_grief_ratio{job=\"1\",url=\"tcp://\\\"b\\\"\\\\c\"} 0.25\n")
+        );
+    }
+
+    #[test]
+    fn parse_pixel_replies() {
+        assert_eq!(
+            parse_pixel(b"PX 3 4 ff8000\n"),
+            Some((3, 4, Pixel::Rgb(255, 128, 0)))
+        );
+        assert_eq!(
+            parse_pixel(b"PX 3 4 FF800080"),
+            Some((3, 4, Pixel::Rgb(255, 128, 0)))
+        );
+        assert_eq!(parse_pixel(b"PX 3 4"), None);
+        assert_eq!(parse_pixel(b"PX 3 4 ff80"), None);
+        assert_eq!(parse_pixel(b"PX -3 4 ff8000"), None);
+        assert_eq!(parse_pixel(b"SIZE 800 600"), None);
+        assert_eq!(parse_pixel(b"PX 3 4 \xff"), None);
+    }
+}
@@ -0,0 +1,434 @@
+use std::{f32::consts::PI, str::FromStr};
+
+use crate::compose::Timeline;
+use crate::image_data::{Correction, Frame, OptimizedImage, Pixel, CORRECTION_BUDGET};
+
+/// Time between two positions in 10ms
+const STEP_DELAY: u16 = 1;
+
+/// How the image moves over the canvas
+#[derive(Debug, Clone, PartialEq)]
+pub enum Motion {
+    /// Bounces off the edges of the canvas
+    Bounce,
+    /// Moves along a closed path from the offset through the given points
+    Path(Vec<(u32, u32)>),
+    /// Follows a Lissajous curve with the given frequencies over the whole canvas
+    Lissajous(u32, u32),
+}
+
+impl FromStr for Motion {
+    type Err = String;
+
+    /// Parses `bounce`, `line:X,Y`, `path:X,Y;X,Y;...` or `lissajous[:A,B]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, args) = s.split_once(':').unwrap_or((s, ""));
+        let pair = |pair: &str| -> Option<(u32, u32)> {
+            let (a, b) = pair.split_once(',')?;
+            Some((a.trim().parse().ok()?, b.trim().parse().ok()?))
+        };
+        let motion = match (name, args) {
+            ("bounce", "") => Some(Motion::Bounce),
+            ("line", point) => pair(point).map(|point| Motion::Path(vec![point])),
+            ("path", points) => points
+                .split(';')
+                .map(pair)
+                .collect::<Option<_>>()
+                .map(Motion::Path),
+            ("lissajous", "") => Some(Motion::Lissajous(3, 2)),
+            ("lissajous", frequencies) => pair(frequencies)
+                .filter(|&(a, b)| a > 0 && b > 0)
+                .map(|(a, b)| Motion::Lissajous(a, b)),
+            _ => None,
+        };
+        motion.ok_or_else(|| {
+            format!(
+                "Invalid motion '{}', expected bounce, line:X,Y, path:X,Y;X,Y;... or lissajous:A,B",
+                s
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MotionOptions {
+    pub motion: Motion,
+    /// Distance moved per step in pixels
+    pub speed: f32,
+    /// Colour drawn where the image has been before
+    pub background: (u8, u8, u8),
+}
+
+impl MotionOptions {
+    pub fn new(motion: Motion) -> Self {
+        MotionOptions {
+            motion,
+            speed: 2.0,
+            background: (0, 0, 0),
+        }
+    }
+}
+
+/// Image drawn at one step of the motion
+struct Placement<'a> {
+    position: (u32, u32),
+    size: (u32, u32),
+    state: &'a Frame,
+    background: Pixel,
+}
+
+impl Placement<'_> {
+    /// Pixel drawn at the position, none outside of the image
+    fn get(&self, x: u32, y: u32) -> Option<Pixel> {
+        let (px, py) = self.position;
+        if x < px || y < py || x >= px + self.size.0 || y >= py + self.size.1 {
+            return None;
+        }
+        match self.state.get(x - px, y - py) {
+            Pixel::Empty => Some(self.background),
+            pixel => Some(pixel),
+        }
+    }
+}
+
+/// Precomputes the animation of an image moving over the canvas
+///
+/// Every step only sends the pixels that change when the image moves,
+/// including the background where it has been before. The image keeps
+/// playing while it moves.
+pub fn animate(
+    frames: Vec<Frame>,
+    start: (u32, u32),
+    canvas: (u32, u32),
+    options: &MotionOptions,
+    similarity: u32,
+) -> OptimizedImage {
+    let timeline = Timeline::new(frames, (0, 0));
+    let size = timeline.states().iter().fold((0, 0), |size, state| {
+        let end = state.end();
+        (size.0.max(end.0), size.1.max(end.1))
+    });
+    let range = (
+        canvas.0.saturating_sub(size.0),
+        canvas.1.saturating_sub(size.1),
+    );
+    let start = (start.0.min(range.0), start.1.min(range.1));
+    let speed = options.speed.max(0.1);
+    let positions = match &options.motion {
+        Motion::Bounce => bounce(start, range, speed),
+        Motion::Path(points) => path(start, points, range, speed),
+        Motion::Lissajous(a, b) => lissajous(*a, *b, range, speed),
+    };
+
+    let background = Pixel::Rgb(
+        options.background.0,
+        options.background.1,
+        options.background.2,
+    );
+    let placements: Vec<Placement> = positions
+        .iter()
+        .enumerate()
+        .map(|(step, &position)| Placement {
+            position,
+            size,
+            state: timeline.state_at(step as u64 * STEP_DELAY as u64),
+            background,
+        })
+        .collect();
+
+    let area = (size.0 * size.1) as usize;
+    // Larger motions redraw less of the image per step
+    let per_step = (CORRECTION_BUDGET / placements.len()).min(area);
+    let start = render(&placements[0]);
+    let (frames, corrections) = placements
+        .iter()
+        .enumerate()
+        .map(|(step, placement)| {
+            let previous = &placements[(step + placements.len() - 1) % placements.len()];
+            let frame = diff(previous, placement, similarity);
+            // Each step redraws another part of the image
+            let corrections = (0..per_step)
+                .map(|i| {
+                    let i = ((step * per_step + i) % area) as u32;
+                    let x = placement.position.0 + i % size.0;
+                    let y = placement.position.1 + i / size.0;
+                    match placement.get(x, y) {
+                        Some(Pixel::Rgb(r, g, b)) => (x, y, (r, g, b)),
+                        _ => unreachable!(),
+                    }
+                })
+                .collect::<Vec<Correction>>();
+            (frame, corrections)
+        })
+        .unzip();
+
+    OptimizedImage {
+        start,
+        frames,
+        corrections,
+    }
+}
+
+fn render(placement: &Placement) -> Frame {
+    let (px, py) = placement.position;
+    let (width, height) = placement.size;
+    let image = (0..width * height)
+        .map(|i| placement.get(px + i % width, py + i / width).unwrap())
+        .collect();
+    Frame::new(image, placement.position, placement.size, STEP_DELAY)
+}
+
+/// Pixels that change between two placements, the background where only the old one was
+fn diff(from: &Placement, to: &Placement, similarity: u32) -> Frame {
+    let offset = (
+        from.position.0.min(to.position.0),
+        from.position.1.min(to.position.1),
+    );
+    let end = (
+        from.position.0.max(to.position.0) + to.size.0,
+        from.position.1.max(to.position.1) + to.size.1,
+    );
+    let size = (end.0 - offset.0, end.1 - offset.1);
+    let image = (0..size.0 * size.1)
+        .map(|i| {
+            let (x, y) = (offset.0 + i % size.0, offset.1 + i / size.0);
+            match (from.get(x, y), to.get(x, y)) {
+                (Some(old), Some(new)) if old.similarity(new) <= similarity => Pixel::Empty,
+                (_, Some(new)) => new,
+                (Some(old), None) if old != to.background => to.background,
+                _ => Pixel::Empty,
+            }
+        })
+        .collect();
+    Frame::new(image, offset, size, STEP_DELAY)
+}
+
+/// Triangle wave between 0 and 1 with a period of 1
+fn triangle(phase: f32) -> f32 {
+    1.0 - (1.0 - 2.0 * phase.rem_euclid(1.0)).abs()
+}
+
+fn bounce(start: (u32, u32), range: (u32, u32), speed: f32) -> Vec<(u32, u32)> {
+    let (rx, ry) = (range.0 as f32, range.1 as f32);
+    // Round trips over each axis per loop, chosen so both axes move at about
+    // the same speed and the image does not just go back and forth
+    let (kx, ky) = if range.0 == 0 || range.1 == 0 {
+        (1, 1)
+    } else {
+        (1..=6u32)
+            .flat_map(|kx| (1..=6u32).map(move |ky| (kx, ky)))
+            .filter(|&(kx, ky)| (kx, ky) != (1, 1) && gcd(kx, ky) == 1)
+            .min_by(|a, b| {
+                let error = |(kx, ky): (u32, u32)| (kx as f32 * rx / (ky as f32 * ry)).ln().abs();
+                error(*a).total_cmp(&error(*b))
+            })
+            .unwrap()
+    };
+    // Every round trip crosses the range twice
+    let steps = (2.0 * (kx as f32 * rx + ky as f32 * ry) / speed)
+        .round()
+        .max(1.0) as usize;
+    let phase = |position: u32, range: f32| {
+        if range > 0.0 {
+            position as f32 / (2.0 * range)
+        } else {
+            0.0
+        }
+    };
+    let (phase_x, phase_y) = (phase(start.0, rx), phase(start.1, ry));
+    (0..steps)
+        .map(|step| {
+            let t = step as f32 / steps as f32;
+            (
+                (triangle(phase_x + kx as f32 * t) * rx).round() as u32,
+                (triangle(phase_y + ky as f32 * t) * ry).round() as u32,
+            )
+        })
+        .collect()
+}
+
+fn path(
+    start: (u32, u32),
+    points: &[(u32, u32)],
+    range: (u32, u32),
+    speed: f32,
+) -> Vec<(u32, u32)> {
+    let mut points: Vec<(f32, f32)> = std::iter::once(start)
+        .chain(points.iter().copied())
+        .map(|(x, y)| (x.min(range.0) as f32, y.min(range.1) as f32))
+        .collect();
+    points.push(points[0]);
+    let length = |a: (f32, f32), b: (f32, f32)| (b.0 - a.0).hypot(b.1 - a.1);
+    let total: f32 = points.windows(2).map(|w| length(w[0], w[1])).sum();
+    let steps = (total / speed).round().max(1.0) as usize;
+
+    let mut positions = Vec::with_capacity(steps);
+    let mut segment = 0;
+    let mut walked = 0.0;
+    for step in 0..steps {
+        let distance = total * step as f32 / steps as f32;
+        while segment + 2 < points.len()
+            && walked + length(points[segment], points[segment + 1]) <= distance
+        {
+            walked += length(points[segment], points[segment + 1]);
+            segment += 1;
+        }
+        let (a, b) = (points[segment], points[segment + 1]);
+        let t = ((distance - walked) / length(a, b)).min(1.0);
+        let t = if t.is_finite() { t } else { 0.0 };
+        positions.push((
+            (a.0 + (b.0 - a.0) * t).round() as u32,
+            (a.1 + (b.1 - a.1) * t).round() as u32,
+        ));
+    }
+    positions
+}
+
+fn lissajous(a: u32, b: u32, range: (u32, u32), speed: f32) -> Vec<(u32, u32)> {
+    let (rx, ry) = (range.0 as f32, range.1 as f32);
+    let point = |angle: f32| {
+        (
+            rx * (1.0 + (a as f32 * angle).cos()) / 2.0,
+            ry * (1.0 + (b as f32 * angle).sin()) / 2.0,
+        )
+    };
+    // Approximate the length of the curve to move at the given speed
+    let samples = 1024;
+    let total: f32 = (0..samples)
+        .map(|i| {
+            let p = point(2.0 * PI * i as f32 / samples as f32);
+            let q = point(2.0 * PI * (i + 1) as f32 / samples as f32);
+            (q.0 - p.0).hypot(q.1 - p.1)
+        })
+        .sum();
+    let steps = (total / speed).round().max(1.0) as usize;
+    (0..steps)
+        .map(|step| {
+            let (x, y) = point(2.0 * PI * step as f32 / steps as f32);
+            (x.round() as u32, y.round() as u32)
+        })
+        .collect()
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_motion() {
+        assert_eq!("bounce".parse(), Ok(Motion::Bounce));
+        assert_eq!("line:10,20".parse(), Ok(Motion::Path(vec![(10, 20)])));
+        assert_eq!("line: 10 , 20 ".parse(), Ok(Motion::Path(vec![(10, 20)])));
+        assert_eq!(
+            "path:0,0;100,0;100,50".parse(),
+            Ok(Motion::Path(vec![(0, 0), (100, 0), (100, 50)]))
+        );
+        assert_eq!("lissajous".parse(), Ok(Motion::Lissajous(3, 2)));
+        assert_eq!("lissajous:5,4".parse(), Ok(Motion::Lissajous(5, 4)));
+    }
+
+    #[test]
+    fn parse_invalid_motion() {
+        for motion in &[
+            "",
+            "spiral",
+            "Bounce",
+            "bounce:1",
+            "line:",
+            "line:10",
+            "line:10,20,30",
+            "line:-1,20",
+            "path:1,2;",
+            "lissajous:3",
+            "lissajous:0,2",
+        ] {
+            assert!(motion.parse::<Motion>().is_err(), "{}", motion);
+        }
+    }
+
+    #[test]
+    fn bounce_positions() {
+        let positions = bounce((6, 0), (10, 0), 2.0);
+        let xs: Vec<u32> = positions.iter().map(|&(x, y)| x + y * 100).collect();
+        assert_eq!(xs, [6, 8, 10, 8, 6, 4, 2, 0, 2, 4]);
+
+        // Both axes bounce off the edges and the image moves at the speed
+        let positions = bounce((0, 0), (10, 5), 1.0);
+        assert_eq!(positions.len(), 40);
+        assert_eq!(positions[0], (0, 0));
+        assert!(positions.contains(&(10, 5)) || positions.contains(&(10, 0)));
+        for (i, &(x, y)) in positions.iter().enumerate() {
+            let (nx, ny) = positions[(i + 1) % positions.len()];
+            assert!(x <= 10 && y <= 5);
+            assert!(x.abs_diff(nx) + y.abs_diff(ny) <= 2, "{:?}", positions);
+        }
+    }
+
+    #[test]
+    fn path_positions() {
+        assert_eq!(
+            path((0, 0), &[(4, 0), (4, 3)], (10, 10), 1.0),
+            [
+                (0, 0),
+                (1, 0),
+                (2, 0),
+                (3, 0),
+                (4, 0),
+                (4, 1),
+                (4, 2),
+                (4, 3),
+                (3, 2),
+                (2, 2),
+                (2, 1),
+                (1, 1),
+            ]
+        );
+        // Points outside of the canvas are moved onto its edge
+        assert_eq!(
+            path((0, 0), &[(30, 0)], (10, 10), 5.0),
+            [(0, 0), (5, 0), (10, 0), (5, 0)]
+        );
+        assert_eq!(path((3, 3), &[(3, 3)], (10, 10), 1.0), [(3, 3)]);
+    }
+
+    #[test]
+    fn lissajous_positions() {
+        let positions = lissajous(1, 1, (10, 10), 1.0);
+        // A circle through the middle of the edges, starting on the right
+        assert_eq!(positions.len(), 31);
+        assert_eq!(positions[0], (10, 5));
+        assert!(positions.contains(&(5, 10)) && positions.contains(&(5, 0)));
+        assert_eq!(positions.iter().map(|&(x, _)| x).min(), Some(0));
+
+        let positions = lissajous(3, 2, (100, 50), 2.0);
+        assert_eq!(positions[0], (100, 25));
+        assert!(positions.iter().all(|&(x, y)| x <= 100 && y <= 50));
+        assert_eq!(positions.iter().map(|&(x, _)| x).min(), Some(0));
+        assert_eq!(positions.iter().map(|&(_, y)| y).max(), Some(50));
+    }
+
+    #[test]
+    fn animate_paints_the_background() {
+        let (red, black) = (Pixel::Rgb(255, 0, 0), Pixel::Rgb(0, 0, 0));
+        let frames = vec![Frame::new(vec![red], (0, 0), (1, 1), 10)];
+        let options = MotionOptions {
+            speed: 1.0,
+            ..MotionOptions::new(Motion::Path(vec![(2, 0)]))
+        };
+        let image = animate(frames, (0, 0), (3, 1), &options, 0);
+        let row = |frame: &Frame| (0..3).map(|x| frame.get(x, 0)).collect::<Vec<_>>();
+        assert_eq!(row(&image.start), [red, Pixel::Empty, Pixel::Empty]);
+        assert_eq!(image.frames.len(), 4);
+        assert_eq!(row(&image.frames[1]), [black, red, Pixel::Empty]);
+        assert_eq!(row(&image.frames[2]), [Pixel::Empty, black, red]);
+        assert_eq!(image.corrections[2], [(2, 0, (255, 0, 0))]);
+    }
+}
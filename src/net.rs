@@ -0,0 +1,794 @@
+use futures::{stream::FuturesUnordered, StreamExt};
+use hyper::{service::Service, Uri};
+use log::debug;
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufWriter, ReadBuf},
+    net::{lookup_host, TcpSocket, TcpStream, UdpSocket},
+    time::timeout,
+};
+use tokio_native_tls::{native_tls, TlsConnector, TlsStream};
+
+use crate::proxy::Proxy;
+use crate::udp::Udp;
+#[cfg(feature = "websocket")]
+use crate::websocket::WebSocket;
+
+/// Port used if the URL has none
+pub const DEFAULT_PORT: u16 = 1337;
+/// Time after which the next address is tried while earlier attempts are still pending
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// Bytes of commands collected into one TLS record
+const TLS_BUFFER_SIZE: usize = 8 * 1024;
+/// Largest UDP payloads that fit into an Ethernet frame
+const UDP_PAYLOAD_V4: usize = 1472;
+const UDP_PAYLOAD_V6: usize = 1452;
+/// Largest payload of a UDP datagram over IPv4
+pub const UDP_PAYLOAD_MAX: usize = 65507;
+
+/// IP versions used to reach servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVersion {
+    Any,
+    V4,
+    V6,
+}
+
+/// How connections to Pixelflut servers are opened
+#[derive(Debug, Clone)]
+pub struct NetOptions {
+    /// Local addresses connections are bound to in turns, any if empty
+    pub bind: Vec<IpAddr>,
+    pub ip_version: IpVersion,
+    /// Proxy that connections are tunnelled through
+    pub proxy: Option<Proxy>,
+    /// PEM file with certificates trusted for `tls://` servers besides the system ones
+    pub tls_ca: Option<PathBuf>,
+    /// Accepts any certificate of `tls://` servers
+    pub tls_insecure: bool,
+    /// Largest payload of the datagrams sent to `udp://` servers, by default
+    /// the largest that fits into an Ethernet frame
+    pub udp_packet_size: Option<usize>,
+    /// Sends pixels to `udp://` servers as binary `PB` commands
+    pub udp_binary: bool,
+    /// Datagrams sent per second to each `udp://` server at most
+    pub udp_packet_rate: Option<u64>,
+}
+
+impl Default for NetOptions {
+    fn default() -> Self {
+        NetOptions {
+            bind: Vec::new(),
+            ip_version: IpVersion::Any,
+            proxy: None,
+            tls_ca: None,
+            tls_insecure: false,
+            udp_packet_size: None,
+            udp_binary: false,
+            udp_packet_rate: None,
+        }
+    }
+}
+
+static OPTIONS: OnceLock<NetOptions> = OnceLock::new();
+/// Bind addresses of each IP version used by the next job connection
+static NEXT_BIND_V4: AtomicUsize = AtomicUsize::new(0);
+static NEXT_BIND_V6: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the options for all connections of the process
+pub fn init(options: NetOptions) {
+    let _ = OPTIONS.set(options);
+}
+
+fn options() -> &'static NetOptions {
+    OPTIONS.get_or_init(NetOptions::default)
+}
+
+/// Splits `host`, `host:port`, `[v6]:port` or a bare IPv6 address into host and port
+pub fn split_host(url: &str, default_port: u16) -> Result<(String, u16), String> {
+    let invalid = || format!("Invalid server address '{}'", url);
+    if url.parse::<Ipv6Addr>().is_ok() {
+        return Ok((url.into(), default_port));
+    }
+    let (host, port) = match url.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+            }
+        }
+        None => match url.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (url, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid())?,
+        None => default_port,
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.into(), port))
+}
+
+/// Addresses of the server in the order they are tried, alternating
+/// between IP versions starting with the one the resolver prefers
+pub async fn resolve(host: &str, port: u16, options: &NetOptions) -> io::Result<Vec<SocketAddr>> {
+    let addrs = interleave(lookup_host((host, port)).await?, options.ip_version);
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no usable addresses", host),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Keeps the addresses of the IP version and alternates between IPv4 and
+/// IPv6, starting with the version of the first address
+fn interleave(
+    resolved: impl IntoIterator<Item = SocketAddr>,
+    ip_version: IpVersion,
+) -> Vec<SocketAddr> {
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) = (Vec::new(), Vec::new());
+    let mut first_v4 = None;
+    for addr in resolved {
+        let wanted = match ip_version {
+            IpVersion::Any => true,
+            IpVersion::V4 => addr.is_ipv4(),
+            IpVersion::V6 => addr.is_ipv6(),
+        };
+        if !wanted {
+            continue;
+        }
+        if *first_v4.get_or_insert(addr.is_ipv4()) == addr.is_ipv4() {
+            first.push(addr);
+        } else {
+            second.push(addr);
+        }
+    }
+    let mut addrs = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+    addrs
+}
+
+/// Connection to a server, plain or wrapped in TLS
+pub enum Stream {
+    Tcp(TcpStream),
+    /// Writes are buffered so that not every command becomes a TLS record of its own
+    Tls(Box<BufWriter<TlsStream<TcpStream>>>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<WebSocket>),
+    Udp(Box<Udp>),
+}
+
+impl Stream {
+    /// Local and remote address of the connection
+    pub fn addrs(&self) -> io::Result<(SocketAddr, SocketAddr)> {
+        let tcp = match self {
+            Stream::Tcp(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref().get_ref().get_ref().get_ref(),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => return stream.get_ref().addrs(),
+            Stream::Udp(udp) => return Ok((udp.local_addr()?, udp.peer_addr())),
+        };
+        Ok((tcp.local_addr()?, tcp.peer_addr()?))
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Udp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Udp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Udp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Udp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connects to the server, through the proxy if there is one, with TLS
+/// if the URL starts with `tls://` and over WebSocket for `ws://` or `wss://`
+pub async fn connect(url: &str) -> io::Result<Stream> {
+    connect_with(url, options()).await
+}
+
+/// Connects to the server like `connect` for flooding, the job connections
+/// take turns in using the bind addresses
+pub async fn connect_job(url: &str) -> io::Result<Stream> {
+    open(url, options(), &Bind::next()).await
+}
+
+/// Connects to the server like `connect` with the given options instead of
+/// the ones of the process
+pub async fn connect_with(url: &str, options: &NetOptions) -> io::Result<Stream> {
+    open(url, options, &Bind::first()).await
+}
+
+async fn open(url: &str, options: &NetOptions, bind: &Bind) -> io::Result<Stream> {
+    let (scheme, address) = match url.split_once("://") {
+        Some((scheme, address)) => (Some(scheme), address),
+        None => (None, url),
+    };
+    let (tls, default_port) = match scheme {
+        None => (false, DEFAULT_PORT),
+        Some("tls") => (true, DEFAULT_PORT),
+        Some("udp") => (false, DEFAULT_PORT),
+        Some("ws") | Some("wss") if cfg!(not(feature = "websocket")) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "WebSocket servers need a build with the websocket feature",
+            ))
+        }
+        Some("ws") => (false, 80),
+        Some("wss") => (true, 443),
+        Some(scheme) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown scheme {}://, expected tls://, ws://, wss:// or udp://",
+                    scheme
+                ),
+            ))
+        }
+    };
+    let websocket = matches!(scheme, Some("ws") | Some("wss"));
+    // WebSocket URLs may have a path
+    let address = match address.find('/') {
+        Some(i) if websocket => &address[..i],
+        _ => address,
+    };
+    let (host, port) = split_host(address, default_port)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if scheme == Some("udp") {
+        return connect_udp(&host, port, options, bind).await;
+    }
+    let stream = connect_to(&host, port, options, bind).await?;
+    let stream = if tls {
+        let stream = tls_connector(options)?
+            .connect(&host, stream)
+            .await
+            .map_err(|err| io::Error::other(format!("TLS: {}", err)))?;
+        // WebSocket messages batch the commands already
+        let capacity = if websocket { 0 } else { TLS_BUFFER_SIZE };
+        Stream::Tls(Box::new(BufWriter::with_capacity(capacity, stream)))
+    } else {
+        Stream::Tcp(stream)
+    };
+    #[cfg(feature = "websocket")]
+    if websocket {
+        let websocket = WebSocket::connect(url, stream).await?;
+        return Ok(Stream::WebSocket(Box::new(websocket)));
+    }
+    Ok(stream)
+}
+
+/// Opens a socket that sends datagrams to the first address of the host
+async fn connect_udp(
+    host: &str,
+    port: u16,
+    options: &NetOptions,
+    bind: &Bind,
+) -> io::Result<Stream> {
+    if options.proxy.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "UDP can't be sent through the proxy",
+        ));
+    }
+    let addr = resolve(host, port, options).await?[0];
+    let (unspecified, payload) = match addr {
+        SocketAddr::V4(_) => (IpAddr::from(Ipv4Addr::UNSPECIFIED), UDP_PAYLOAD_V4),
+        SocketAddr::V6(_) => (IpAddr::from(Ipv6Addr::UNSPECIFIED), UDP_PAYLOAD_V6),
+    };
+    let local = bind.local(addr, options)?.unwrap_or(unspecified);
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    socket.connect(addr).await?;
+    Ok(Stream::Udp(Box::new(Udp::new(
+        socket,
+        addr,
+        options.udp_packet_size.unwrap_or(payload),
+        options.udp_binary,
+        options.udp_packet_rate,
+    ))))
+}
+
+fn tls_connector(options: &NetOptions) -> io::Result<TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(path) = &options.tls_ca {
+        let pem = std::fs::read(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+        let certificate = native_tls::Certificate::from_pem(&pem)
+            .map_err(|err| io::Error::other(format!("{}: {}", path.display(), err)))?;
+        builder.add_root_certificate(certificate);
+    }
+    if options.tls_insecure {
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    Ok(builder.build().map_err(io::Error::other)?.into())
+}
+
+async fn connect_to(
+    host: &str,
+    port: u16,
+    options: &NetOptions,
+    bind: &Bind,
+) -> io::Result<TcpStream> {
+    match &options.proxy {
+        Some(proxy) => {
+            let mut stream = connect_direct(&proxy.host, proxy.port, options, bind)
+                .await
+                .map_err(|err| io::Error::new(err.kind(), format!("Proxy: {}", err)))?;
+            proxy.open(&mut stream, host, port).await?;
+            debug!("Tunnelled to {}:{} through the proxy", host, port);
+            Ok(stream)
+        }
+        None => connect_direct(host, port, options, bind).await,
+    }
+}
+
+/// Connects to the host, racing its addresses with happy eyeballs
+///
+/// The next address is tried when the previous attempt fails or does not
+/// succeed within 250ms, the first connection that is established wins.
+async fn connect_direct(
+    host: &str,
+    port: u16,
+    options: &NetOptions,
+    bind: &Bind,
+) -> io::Result<TcpStream> {
+    let mut addrs = resolve(host, port, options).await?.into_iter();
+    let mut pending = FuturesUnordered::new();
+    let mut error = None;
+    loop {
+        if let Some(addr) = addrs.next() {
+            pending.push(async move { (addr, connect_addr(addr, options, bind).await) });
+        }
+        let attempt = if addrs.len() > 0 {
+            match timeout(ATTEMPT_DELAY, pending.next()).await {
+                Ok(attempt) => attempt,
+                Err(_) => continue,
+            }
+        } else {
+            pending.next().await
+        };
+        match attempt {
+            Some((_, Ok(stream))) => return Ok(stream),
+            Some((addr, Err(err))) => {
+                debug!("Connecting to {} failed: {}", addr, err);
+                error = Some(err);
+            }
+            None => return Err(error.unwrap()),
+        }
+    }
+}
+
+/// Connector for hyper clients that opens connections like `connect`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Connector;
+
+impl Service<Uri> for Connector {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        Box::pin(async move {
+            let host = uri.host().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("No host in {}", uri))
+            })?;
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            connect_to(host, port, options(), &Bind::first()).await
+        })
+    }
+}
+
+async fn connect_addr(
+    addr: SocketAddr,
+    options: &NetOptions,
+    bind: &Bind,
+) -> io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(local) = bind.local(addr, options)? {
+        socket.bind(SocketAddr::new(local, 0))?;
+    }
+    socket.connect(addr).await
+}
+
+/// Bind addresses of one connection, picked once per IP version so that
+/// all attempts and the proxy hop of the connection use the same ones
+struct Bind {
+    /// Whether the addresses are taken in turns with the other job connections
+    turns: bool,
+    v4: OnceLock<IpAddr>,
+    v6: OnceLock<IpAddr>,
+}
+
+impl Bind {
+    /// Uses the first bind address of each IP version
+    fn first() -> Self {
+        Bind {
+            turns: false,
+            v4: OnceLock::new(),
+            v6: OnceLock::new(),
+        }
+    }
+
+    /// Uses the next bind address of each IP version
+    fn next() -> Self {
+        Bind {
+            turns: true,
+            ..Bind::first()
+        }
+    }
+
+    /// Bind address of the same IP version as the server address
+    fn local(&self, addr: SocketAddr, options: &NetOptions) -> io::Result<Option<IpAddr>> {
+        if options.bind.is_empty() {
+            return Ok(None);
+        }
+        let (local, next) = match addr {
+            SocketAddr::V4(_) => (&self.v4, &NEXT_BIND_V4),
+            SocketAddr::V6(_) => (&self.v6, &NEXT_BIND_V6),
+        };
+        if let Some(local) = local.get() {
+            return Ok(Some(*local));
+        }
+        let candidates: Vec<IpAddr> = options
+            .bind
+            .iter()
+            .filter(|local| local.is_ipv4() == addr.is_ipv4())
+            .copied()
+            .collect();
+        if candidates.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("No bind address can reach {}", addr),
+            ));
+        }
+        let i = match self.turns {
+            true => next.fetch_add(1, Ordering::Relaxed),
+            false => 0,
+        };
+        Ok(Some(
+            *local.get_or_init(|| candidates[i % candidates.len()]),
+        ))
+    }
+}
+
+/// Parses an IP address or the name of a network interface, which stands
+/// for all of its addresses
+pub fn parse_bind(s: &str) -> Result<Vec<IpAddr>, String> {
+    if let Ok(ip) = s.trim_start_matches('[').trim_end_matches(']').parse() {
+        return Ok(vec![ip]);
+    }
+    let addresses = interface_addresses(s)?;
+    if addresses.is_empty() {
+        return Err(format!(
+            "'{}' is neither an IP address nor an interface with addresses",
+            s
+        ));
+    }
+    Ok(addresses)
+}
+
+#[cfg(unix)]
+fn interface_addresses(name: &str) -> Result<Vec<IpAddr>, String> {
+    use std::ffi::CStr;
+
+    let mut addresses = Vec::new();
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs fills a linked list that is only read before it is freed
+    unsafe {
+        if libc::getifaddrs(&mut list) != 0 {
+            return Err(io::Error::last_os_error().to_string());
+        }
+        let mut entry = list;
+        while let Some(ifaddr) = entry.as_ref() {
+            entry = ifaddr.ifa_next;
+            if ifaddr.ifa_addr.is_null()
+                || CStr::from_ptr(ifaddr.ifa_name).to_bytes() != name.as_bytes()
+            {
+                continue;
+            }
+            match (*ifaddr.ifa_addr).sa_family as i32 {
+                libc::AF_INET => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+                    addresses.push(IpAddr::from(
+                        u32::from_be(addr.sin_addr.s_addr).to_be_bytes(),
+                    ));
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in6);
+                    let ip = std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                    // Link-local addresses need a scope and can't reach servers anyway
+                    if ip.segments()[0] & 0xffc0 != 0xfe80 {
+                        addresses.push(IpAddr::V6(ip));
+                    }
+                }
+                _ => {}
+            }
+        }
+        libc::freeifaddrs(list);
+    }
+    Ok(addresses)
+}
+
+#[cfg(not(unix))]
+fn interface_addresses(_name: &str) -> Result<Vec<IpAddr>, String> {
+    Err("Interfaces can only be given by name on unix, use an IP address".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Read,
+        net::TcpListener,
+        path::Path,
+        thread::{self, JoinHandle},
+    };
+    use tokio::{io::AsyncWriteExt, runtime::Runtime};
+
+    /// Self-signed certificate for localhost, 127.0.0.1 and ::1, made with
+    /// `openssl req -x509` and exported with the password `pixelflut`
+    const IDENTITY: &[u8] = include_bytes!("../tests/fixtures/localhost.p12");
+
+    fn certificate() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/localhost.pem")
+    }
+
+    /// TLS server for one connection that returns the commands it received,
+    /// none if the handshake failed
+    fn tls_server() -> (u16, JoinHandle<Option<Vec<u8>>>) {
+        let identity = native_tls::Identity::from_pkcs12(IDENTITY, "pixelflut").unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut stream = acceptor.accept(listener.accept().unwrap().0).ok()?;
+            let mut commands = Vec::new();
+            stream.read_to_end(&mut commands).ok()?;
+            Some(commands)
+        });
+        (port, handle)
+    }
+
+    fn flood(url: &str, options: &NetOptions) -> io::Result<()> {
+        Runtime::new().unwrap().block_on(async {
+            let mut stream = connect_with(url, options).await?;
+            stream.write_all(b"PX 1 2 ff0000\n").await?;
+            stream.shutdown().await
+        })
+    }
+
+    #[test]
+    fn tls_rejects_unknown_certificate() {
+        let (port, server) = tls_server();
+        let url = format!("tls://127.0.0.1:{}", port);
+        let err = flood(&url, &NetOptions::default()).unwrap_err();
+        assert!(err.to_string().starts_with("TLS: "), "{}", err);
+        assert_eq!(server.join().unwrap(), None);
+    }
+
+    #[test]
+    fn tls_trusts_ca() {
+        let (port, server) = tls_server();
+        let options = NetOptions {
+            tls_ca: Some(certificate()),
+            ..NetOptions::default()
+        };
+        flood(&format!("tls://127.0.0.1:{}", port), &options).unwrap();
+        assert_eq!(server.join().unwrap().unwrap(), b"PX 1 2 ff0000\n");
+    }
+
+    #[test]
+    fn tls_insecure_accepts_any_certificate() {
+        let (port, server) = tls_server();
+        let options = NetOptions {
+            tls_insecure: true,
+            ..NetOptions::default()
+        };
+        flood(&format!("tls://127.0.0.1:{}", port), &options).unwrap();
+        assert_eq!(server.join().unwrap().unwrap(), b"PX 1 2 ff0000\n");
+    }
+
+    #[test]
+    fn tls_missing_ca() {
+        let options = NetOptions {
+            tls_ca: Some("tests/fixtures/missing.pem".into()),
+            ..NetOptions::default()
+        };
+        let err = tls_connector(&options).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("missing.pem"), "{}", err);
+    }
+
+    #[test]
+    fn parse_bind_addresses() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(parse_bind("192.0.2.1"), Ok(vec![v4]));
+        assert_eq!(parse_bind("2001:db8::1"), Ok(vec![v6]));
+        assert_eq!(parse_bind("[2001:db8::1]"), Ok(vec![v6]));
+        assert!(parse_bind("no-such-interface0").is_err());
+        assert!(parse_bind("192.0.2.1:1337").is_err());
+    }
+
+    #[test]
+    fn bind_addresses_per_connection() {
+        let options = NetOptions {
+            bind: ["192.0.2.1", "192.0.2.2", "2001:db8::1"]
+                .iter()
+                .map(|ip| ip.parse().unwrap())
+                .collect(),
+            ..NetOptions::default()
+        };
+        let v4: SocketAddr = "198.51.100.1:1337".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::2]:1337".parse().unwrap();
+        let local = |bind: &Bind, addr| bind.local(addr, &options).unwrap().unwrap();
+
+        // Every attempt of a connection binds to the same address
+        let (first, second) = (Bind::next(), Bind::next());
+        assert_eq!(local(&first, v4), local(&first, v4));
+        assert_ne!(local(&first, v4), local(&second, v4));
+        assert_eq!(local(&first, v6), options.bind[2]);
+        assert_eq!(local(&second, v6), options.bind[2]);
+
+        // Other connections don't take turns
+        assert_eq!(local(&Bind::first(), v4), options.bind[0]);
+        assert_eq!(local(&Bind::first(), v4), options.bind[0]);
+
+        let options = NetOptions {
+            bind: vec![options.bind[2]],
+            ..NetOptions::default()
+        };
+        assert!(Bind::first().local(v4, &options).is_err());
+    }
+
+    #[test]
+    fn split_host_forms() {
+        let split = |url| split_host(url, DEFAULT_PORT);
+        assert_eq!(split("example.org"), Ok(("example.org".into(), 1337)));
+        assert_eq!(split("example.org:1234"), Ok(("example.org".into(), 1234)));
+        assert_eq!(split("2001:db8::1"), Ok(("2001:db8::1".into(), 1337)));
+        assert_eq!(split("::1"), Ok(("::1".into(), 1337)));
+        assert_eq!(split("[2001:db8::1]"), Ok(("2001:db8::1".into(), 1337)));
+        assert_eq!(
+            split("[2001:db8::1]:1234"),
+            Ok(("2001:db8::1".into(), 1234))
+        );
+        assert_eq!(
+            split_host("example.org", 80),
+            Ok(("example.org".into(), 80))
+        );
+        assert!(split("example.org:").is_err());
+        assert!(split(":1234").is_err());
+        assert!(split("").is_err());
+        assert!(split("[]:1234").is_err());
+        assert!(split("[2001:db8::1]1234").is_err());
+        assert!(split("[2001:db8::1").is_err());
+        assert!(split("example.org:http").is_err());
+    }
+
+    #[test]
+    fn interleave_ip_versions() {
+        let addrs = |list: &[&str]| -> Vec<SocketAddr> {
+            list.iter().map(|addr| addr.parse().unwrap()).collect()
+        };
+        let resolved = addrs(&[
+            "[2001:db8::1]:1337",
+            "[2001:db8::2]:1337",
+            "[2001:db8::3]:1337",
+            "192.0.2.1:1337",
+            "192.0.2.2:1337",
+        ]);
+        assert_eq!(
+            interleave(resolved.clone(), IpVersion::Any),
+            addrs(&[
+                "[2001:db8::1]:1337",
+                "192.0.2.1:1337",
+                "[2001:db8::2]:1337",
+                "192.0.2.2:1337",
+                "[2001:db8::3]:1337",
+            ])
+        );
+        assert_eq!(
+            interleave(resolved.iter().rev().copied(), IpVersion::Any),
+            addrs(&[
+                "192.0.2.2:1337",
+                "[2001:db8::3]:1337",
+                "192.0.2.1:1337",
+                "[2001:db8::2]:1337",
+                "[2001:db8::1]:1337",
+            ])
+        );
+        assert_eq!(
+            interleave(resolved.clone(), IpVersion::V4),
+            addrs(&["192.0.2.1:1337", "192.0.2.2:1337"])
+        );
+        assert_eq!(
+            interleave(resolved.clone(), IpVersion::V6),
+            addrs(&[
+                "[2001:db8::1]:1337",
+                "[2001:db8::2]:1337",
+                "[2001:db8::3]:1337",
+            ])
+        );
+        assert!(interleave(addrs(&["192.0.2.1:1337"]), IpVersion::V6).is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+use log::info;
+use rand::{seq::SliceRandom, thread_rng};
+use serde::Deserialize;
+use std::{error::Error, path::Path};
+use tokio::runtime::Runtime;
+
+use crate::compose::Timeline;
+use crate::config::{self, Job};
+use crate::image_data::{self, FlutInstructions, Frame, Pixel, TimedLoop};
+use crate::source::Source;
+
+/// Number of images a wipe or dissolve is split into
+const TRANSITION_STEPS: u32 = 16;
+
+/// How one entry of a playlist replaces the one before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transition {
+    #[default]
+    Cut,
+    /// Reveals the entry from left to right
+    Wipe,
+    /// Reveals the pixels of the entry in random order
+    Dissolve,
+}
+
+/// How long an entry is shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Loops(u32),
+    /// Rounded to whole loops of the entry
+    Seconds(f32),
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub source: Source,
+    pub length: Length,
+    pub transition: Transition,
+    /// Length of the transition to this entry in seconds
+    pub transition_time: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    pub entries: Vec<Entry>,
+    /// Colour drawn where the previous entry was but the next one is transparent
+    pub background: (u8, u8, u8),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlaylistFile {
+    background: Option<String>,
+    entries: Vec<EntryFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct EntryFile {
+    source: String,
+    duration: Option<f32>,
+    loops: Option<u32>,
+    #[serde(default)]
+    transition: Transition,
+    transition_time: Option<f32>,
+}
+
+/// Reads a TOML or YAML playlist, sources are relative to the playlist file
+pub fn load(path: &str) -> Result<Playlist, Box<dyn Error>> {
+    let path = Path::new(path);
+    let file: PlaylistFile = config::parse(path)?;
+    if file.entries.is_empty() {
+        return Err("The playlist contains no entries".into());
+    }
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let entries = file
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let source: Source = config::resolve(base, &entry.source).parse()?;
+            if let Source::Playlist(_) = source {
+                return Err("Playlists can't contain other playlists".into());
+            }
+            let length = match (entry.duration, entry.loops) {
+                (Some(_), Some(_)) => {
+                    return Err(format!(
+                        "The entry {} has both a duration and a loop count",
+                        entry.source
+                    )
+                    .into())
+                }
+                (Some(seconds), None) if seconds.is_finite() && seconds >= 0.0 => {
+                    Length::Seconds(seconds)
+                }
+                (Some(_), None) => return Err("Durations must be positive".into()),
+                (None, loops) => Length::Loops(loops.unwrap_or(1).max(1)),
+            };
+            Ok(Entry {
+                source,
+                length,
+                transition: entry.transition,
+                transition_time: entry.transition_time.unwrap_or(1.0).max(0.0),
+            })
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+    let background = match file.background {
+        Some(hex) => {
+            Pixel::hex_to_rgb(&hex).ok_or_else(|| format!("Invalid background colour '{}'", hex))?
+        }
+        None => (0, 0, 0),
+    };
+    Ok(Playlist {
+        entries,
+        background,
+    })
+}
+
+/// Pre-generates the commands of all entries and the transitions between them
+///
+/// Every entry and transition becomes a run of frames and the sequence plays
+/// each entry for its number of loops, so the flooding loop moves on to the
+/// next entry without reconnecting.
+pub fn prepare(job: &Job, path: &str, rt: &Runtime) -> Result<FlutInstructions, Box<dyn Error>> {
+    if job.motion.is_some() {
+        return Err("Playlists can't be moved".into());
+    }
+    let playlist = load(path)?;
+    let (r, g, b) = playlist.background;
+    let background = Pixel::Rgb(r, g, b);
+
+    let entries = playlist
+        .entries
+        .iter()
+        .map(|entry| {
+            info!("📼 Entry {}", entry.source);
+            let frames = crate::load_frames(&entry.source, job, rt)?;
+            if frames.is_empty() {
+                return Err(format!("The entry {} contains no frames", entry.source).into());
+            }
+            Ok(frames)
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    // First and last complete images of each entry to build the transitions from
+    let ends: Vec<(Frame, Frame)> = entries
+        .iter()
+        .map(|frames| {
+            let timeline = Timeline::new(frames.clone(), (0, 0));
+            let states = timeline.states();
+            (states[0].clone(), states[states.len() - 1].clone())
+        })
+        .collect();
+
+    // Each entry is followed by the transition to the next one, so the
+    // commands start with the first entry. Transitions take real time and
+    // durations are kept in seconds, so they don't depend on the time factor.
+    let mut segments = Vec::new();
+    for (i, (entry, frames)) in playlist.entries.iter().zip(entries).enumerate() {
+        segments.push((frames, entry.length, false));
+        let next = (i + 1) % playlist.entries.len();
+        if next != i {
+            let transition = transition(
+                &ends[i].1,
+                &ends[next].0,
+                &playlist.entries[next],
+                background,
+            );
+            if !transition.is_empty() {
+                segments.push((transition, Length::Loops(1), true));
+            }
+        }
+    }
+
+    let extent = segments
+        .iter()
+        .map(|(frames, _, _)| crate::extent(frames))
+        .fold((0, 0), |size, end| (size.0.max(end.0), size.1.max(end.1)));
+    let tiling = crate::tiling(job, extent, rt)?;
+
+    info!("📝 Generating Commands...");
+    let mut instructions = FlutInstructions {
+        start: Vec::new(),
+        frames: Vec::new(),
+        sequence: Vec::new(),
+        fixed_delays: Vec::new(),
+        timed_loops: Vec::new(),
+        epoch: None,
+    };
+    for (i, (frames, length, fixed)) in segments.into_iter().enumerate() {
+        let optimized = image_data::optimize_image(frames, job.similarity);
+        let segment = image_data::optimized_image_to_instructions(
+            optimized,
+            &tiling,
+            &mut if job.shuffle {
+                Some(thread_rng())
+            } else {
+                None
+            }
+            .as_mut(),
+        );
+        if i == 0 {
+            instructions.start = segment.start;
+        }
+        let first = instructions.frames.len();
+        let start = instructions.sequence.len();
+        let loops = match length {
+            Length::Loops(loops) => loops,
+            Length::Seconds(_) => 1,
+        };
+        for _ in 0..loops {
+            instructions
+                .sequence
+                .extend(segment.sequence.iter().map(|frame| first + frame));
+        }
+        if let Length::Seconds(seconds) = length {
+            instructions.timed_loops.push(TimedLoop {
+                positions: start..instructions.sequence.len(),
+                duration: (seconds * 100.0).round() as u64,
+            });
+        }
+        instructions
+            .fixed_delays
+            .extend(std::iter::repeat_n(fixed, segment.frames.len()));
+        instructions.frames.extend(segment.frames);
+    }
+    Ok(instructions)
+}
+
+/// Images that change the last image of one entry into the first of the next
+fn transition(from: &Frame, to: &Frame, entry: &Entry, background: Pixel) -> Vec<Frame> {
+    let offset = (
+        from.offset().0.min(to.offset().0),
+        from.offset().1.min(to.offset().1),
+    );
+    let end = (from.end().0.max(to.end().0), from.end().1.max(to.end().1));
+    let size = (end.0 - offset.0, end.1 - offset.1);
+    let area = (size.0 * size.1) as usize;
+    let position = |i: usize| (offset.0 + i as u32 % size.0, offset.1 + i as u32 / size.0);
+    let target = |x, y| match (from.get(x, y), to.get(x, y)) {
+        (Pixel::Rgb(..), Pixel::Empty) => background,
+        (_, pixel) => pixel,
+    };
+
+    if entry.transition == Transition::Cut || entry.transition_time == 0.0 {
+        // The first image of the entry is drawn completely, only clear the rest
+        let image: Vec<Pixel> = (0..area)
+            .map(|i| {
+                let (x, y) = position(i);
+                match to.get(x, y) {
+                    Pixel::Empty => target(x, y),
+                    _ => Pixel::Empty,
+                }
+            })
+            .collect();
+        if image.iter().all(|&pixel| pixel == Pixel::Empty) {
+            return Vec::new();
+        }
+        return vec![Frame::new(image, offset, size, 0)];
+    }
+
+    // Step at which each pixel switches to the next entry
+    let switch: Vec<u32> = match entry.transition {
+        Transition::Wipe => (0..area)
+            .map(|i| (i as u32 % size.0) * TRANSITION_STEPS / size.0)
+            .collect(),
+        _ => {
+            let mut order: Vec<usize> = (0..area).collect();
+            order.shuffle(&mut thread_rng());
+            let mut switch = vec![0; area];
+            for (rank, &i) in order.iter().enumerate() {
+                switch[i] = (rank as u64 * TRANSITION_STEPS as u64 / area as u64) as u32;
+            }
+            switch
+        }
+    };
+    let delay = (entry.transition_time * 100.0 / TRANSITION_STEPS as f32)
+        .round()
+        .clamp(1.0, u16::MAX as f32) as u16;
+    (1..=TRANSITION_STEPS)
+        .map(|step| {
+            let image = (0..area)
+                .map(|i| {
+                    let (x, y) = position(i);
+                    if switch[i] < step {
+                        target(x, y)
+                    } else {
+                        from.get(x, y)
+                    }
+                })
+                .collect();
+            Frame::new(image, offset, size, delay)
+        })
+        .collect()
+}
@@ -0,0 +1,104 @@
+use log::{info, warn};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+    time::SystemTime,
+};
+use tokio::time::interval;
+
+use crate::control::JobHandle;
+use crate::source::Source;
+
+/// Time between two checks for changed source files
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Local files of a loaded source and their modification times before loading
+#[derive(Debug, Clone)]
+pub struct SourceFiles {
+    paths: Vec<PathBuf>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl SourceFiles {
+    pub fn of(source: &Source) -> Self {
+        let paths = source.paths();
+        SourceFiles {
+            modified: fingerprint(&paths),
+            paths,
+        }
+    }
+}
+
+/// Reloads jobs whose source files changed
+///
+/// A job is only reloaded once its files stopped changing for one interval,
+/// so files that are still being written are not loaded half way. The files
+/// are compared to the ones the current source of the job was loaded from.
+pub async fn watch_sources(jobs: Arc<Vec<JobHandle>>) {
+    let mut seen = vec![Vec::new(); jobs.len()];
+    // Files that could not be loaded are only tried again once they change
+    let mut failed = vec![None; jobs.len()];
+    let mut ticks = interval(POLL_INTERVAL);
+    loop {
+        ticks.tick().await;
+        for (id, job) in jobs.iter().enumerate() {
+            let files = job.files();
+            let current = fingerprint(&files.paths);
+            let stable = current == seen[id];
+            seen[id] = current.clone();
+            if current == files.modified || !stable || failed[id].as_ref() == Some(&current) {
+                continue;
+            }
+            info!("🔄 Source of {} changed, reloading...", job.job().url);
+            if let Err(err) = job.reload().await {
+                warn!("⚠️ Reloading failed, keeping the old frames: {}", err);
+                failed[id] = Some(current);
+            }
+        }
+    }
+}
+
+/// Reloads all jobs when the process receives SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_hangup(jobs: Arc<Vec<JobHandle>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            warn!("⚠️ Can't listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("🔄 Received SIGHUP, reloading...");
+        for job in jobs.iter() {
+            if let Err(err) = job.reload().await {
+                warn!("⚠️ Reloading failed, keeping the old frames: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_hangup(_jobs: Arc<Vec<JobHandle>>) {}
+
+fn fingerprint(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths.iter().map(|path| modified(path)).collect()
+}
+
+/// Latest modification time of a file or of a directory and the files in it
+fn modified(path: &Path) -> Option<SystemTime> {
+    let meta = fs::metadata(path).ok()?;
+    let mut time = meta.modified().ok()?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path).ok()?.flatten() {
+            if let Ok(entry_time) = entry.metadata().and_then(|meta| meta.modified()) {
+                time = time.max(entry_time);
+            }
+        }
+    }
+    Some(time)
+}
@@ -0,0 +1,269 @@
+use std::{
+    borrow::Borrow,
+    error::Error,
+    fmt,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use futures::TryStreamExt;
+use hyper::{
+    body::{Bytes, HttpBody},
+    Client,
+};
+use log::info;
+use tokio::runtime::Runtime;
+
+use crate::compose::{self, Layer};
+use crate::generator::{self, Generator, GeneratorOptions};
+use crate::image_data::{self, Frame, GifSource};
+use crate::image_file;
+use crate::net;
+use crate::playlist;
+use crate::text::{self, TextOptions};
+
+/// Where the frames of the animation come from
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// GIF file path or URL
+    Gif(String),
+    /// Text rasterised with a bitmap or TrueType font
+    Text(TextOptions),
+    /// Procedurally generated frames
+    Generator(GeneratorOptions),
+    /// Directory with a numbered sequence of PNG or PPM images
+    FramesDir { path: String, fps: f32 },
+    /// Single PNG or PPM image
+    Still(String),
+    /// Several sources stacked on top of each other
+    Layers(Vec<Layer>),
+    /// File listing sources that are shown one after another
+    Playlist(String),
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    /// Parses `text:<TEXT>`, `gen:<NAME>`, `playlist:<FILE>`, a directory of frames, a PNG or PPM image or a GIF path or URL
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("playlist:") {
+            Ok(Source::Playlist(path.into()))
+        } else if let Some(text) = s.strip_prefix("text:") {
+            Ok(Source::Text(TextOptions {
+                text: text.into(),
+                ..TextOptions::default()
+            }))
+        } else if let Some(name) = s.strip_prefix("gen:") {
+            Ok(Source::Generator(GeneratorOptions {
+                generator: name.parse()?,
+                ..GeneratorOptions::default()
+            }))
+        } else if Path::new(s).is_dir() {
+            Ok(Source::FramesDir {
+                path: s.into(),
+                fps: 10.0,
+            })
+        } else if is_still(s) {
+            Ok(Source::Still(s.into()))
+        } else {
+            Ok(Source::Gif(s.into()))
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    /// Describes the source in the syntax of the arguments
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Gif(path) | Source::Still(path) => write!(f, "{}", path),
+            Source::FramesDir { path, .. } => write!(f, "{}", path),
+            Source::Text(options) => write!(f, "text:{}", options.text),
+            Source::Playlist(path) => write!(f, "playlist:{}", path),
+            Source::Generator(options) => {
+                write!(
+                    f,
+                    "gen:{}",
+                    format!("{:?}", options.generator).to_lowercase()
+                )
+            }
+            Source::Layers(layers) => {
+                for (i, layer) in layers.iter().enumerate() {
+                    let separator = if i > 0 { " " } else { "" };
+                    let (x, y) = layer.offset;
+                    write!(f, "{}{}@{},{}", separator, layer.source, x, y)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Source {
+    /// Time factor used if none is configured
+    pub fn default_time_factor(&self) -> u32 {
+        match self {
+            // The clock only shows the right time if it is not slowed down
+            Source::Generator(options) if options.generator == Generator::Clock => 1,
+            Source::Layers(layers) => layers
+                .iter()
+                .map(|layer| layer.source.default_time_factor())
+                .min()
+                .unwrap_or(10),
+            Source::Playlist(path) => playlist::load(path)
+                .ok()
+                .and_then(|playlist| {
+                    playlist
+                        .entries
+                        .iter()
+                        .map(|entry| entry.source.default_time_factor())
+                        .min()
+                })
+                .unwrap_or(10),
+            _ => 10,
+        }
+    }
+    /// Wall-clock time at which the first frame is due, for sources that show the time
+    pub fn epoch(&self) -> Option<SystemTime> {
+        match self {
+            Source::Generator(options) if options.generator == Generator::Clock => {
+                Some(generator::clock_epoch())
+            }
+            // Compositions loop over the longest layer, which is the day of the clock
+            Source::Layers(layers) => layers.iter().find_map(|layer| layer.source.epoch()),
+            _ => None,
+        }
+    }
+    /// Local files and directories the frames are loaded from
+    pub fn paths(&self) -> Vec<PathBuf> {
+        match self {
+            Source::Gif(path)
+                if path == "-" || path.starts_with("http:") || path.starts_with("https:") =>
+            {
+                Vec::new()
+            }
+            Source::Gif(path) | Source::Still(path) | Source::FramesDir { path, .. } => {
+                vec![path.into()]
+            }
+            Source::Text(options) => options.font.iter().map(PathBuf::from).collect(),
+            Source::Generator(options) => options.text.font.iter().map(PathBuf::from).collect(),
+            Source::Layers(layers) => layers
+                .iter()
+                .flat_map(|layer| layer.source.paths())
+                .collect(),
+            Source::Playlist(path) => {
+                let entries = playlist::load(path)
+                    .map(|playlist| playlist.entries)
+                    .unwrap_or_default();
+                std::iter::once(PathBuf::from(path))
+                    .chain(entries.iter().flat_map(|entry| entry.source.paths()))
+                    .collect()
+            }
+        }
+    }
+    pub fn load(&self, rt: &Runtime) -> Result<Vec<Frame>, Box<dyn Error>> {
+        match self {
+            Source::Gif(path) => load_gif(path, rt),
+            Source::Text(options) => {
+                info!("🔤 Rendering text...");
+                text::render_text(options)
+            }
+            Source::Generator(options) => {
+                info!("🎨 Generating frames...");
+                generator::generate(options)
+            }
+            Source::FramesDir { path, fps } => {
+                info!("🎞️ Loading frames...");
+                image_file::load_frames_dir(Path::new(path), *fps)
+            }
+            Source::Still(path) => {
+                info!("🖼️ Loading image...");
+                let mut frame = image_file::load_still(Path::new(path))?;
+                frame.set_delay(10);
+                Ok(vec![frame])
+            }
+            Source::Layers(layers) => {
+                let frames = layers
+                    .iter()
+                    .map(|layer| Ok((layer.source.load(rt)?, layer.offset)))
+                    .collect::<Result<_, Box<dyn Error>>>()?;
+                info!("🥞 Composing layers...");
+                Ok(compose::compose(frames))
+            }
+            // Entries are prepared one by one, see `playlist::prepare`
+            Source::Playlist(_) => Err("Playlists can't be used inside other sources".into()),
+        }
+    }
+}
+
+/// Whether the path refers to a PNG or PPM image
+pub fn is_still(path: &str) -> bool {
+    let is_url = path.starts_with("http:") || path.starts_with("https:");
+    let ext = Path::new(path).extension().and_then(|ext| ext.to_str());
+    !is_url && ext.is_some_and(|ext| matches!(ext.to_ascii_lowercase().as_str(), "png" | "ppm"))
+}
+
+fn load_gif(file_path: &str, rt: &Runtime) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let gif = match file_path {
+        n if n.starts_with("http:") || n.starts_with("https:") => {
+            let uri = file_path.parse()?;
+            rt.block_on(async move {
+                let https = hyper_tls::HttpsConnector::new_with_connector(net::Connector);
+                let http_client = Client::builder().build::<_, hyper::Body>(https);
+                let res = http_client.get(uri).await?;
+                let gif_data = res.into_body();
+                let size_hint = gif_data.size_hint();
+
+                let mut data = size_hint
+                    .exact()
+                    .or_else(|| size_hint.upper())
+                    .map(|size| size as usize)
+                    .map_or_else(Vec::new, Vec::with_capacity);
+                let bytes_vec: Vec<Bytes> = gif_data.try_collect().await?;
+                for bytes in bytes_vec {
+                    data.extend(bytes);
+                }
+
+                info!("🔽 Downloaded file");
+
+                Result::<_, Box<dyn Error>>::Ok(GifSource::Vec(data))
+            })?
+        }
+        "-" => {
+            info!("📥 Reading from stdin...");
+            GifSource::Stdin(io::stdin())
+        }
+        _ => {
+            let file = File::open(file_path)?;
+            if is_fifo(&file) {
+                info!("📥 Reading from pipe...");
+            }
+            GifSource::File(file)
+        }
+    };
+
+    info!("🖼️ Parsing image...");
+
+    let frames = match gif {
+        GifSource::File(file) => image_data::load_image(file),
+        GifSource::Vec(vec) => image_data::load_image::<&[u8]>(vec.borrow()),
+        GifSource::Stdin(stdin) => image_data::load_image(stdin.lock()),
+    }?;
+    if frames.is_empty() {
+        return Err("The GIF contains no frames".into());
+    }
+    Ok(frames)
+}
+
+#[cfg(unix)]
+fn is_fifo(file: &File) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    file.metadata().is_ok_and(|meta| meta.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_file: &File) -> bool {
+    false
+}
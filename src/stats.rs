@@ -0,0 +1,147 @@
+use log::info;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+use crate::control::JobHandle;
+
+/// Counters of a flooding loop, shared with the status line and the control API
+#[derive(Debug, Default)]
+pub struct Stats {
+    bytes: AtomicU64,
+    pixels: AtomicU64,
+    frames: AtomicU64,
+    /// Rounds of corrections sent before the frames ended, in thousandths
+    correction_rounds: AtomicU64,
+    /// Total time by which frames ended late, in ms
+    lag: AtomicU64,
+    reconnects: AtomicU64,
+    connection_errors: AtomicU64,
+    /// Index of the frame that is being played
+    frame: AtomicU64,
+    /// Pixels read back from the server and how many of them were griefed
+    sampled: AtomicU64,
+    griefed: AtomicU64,
+    /// Share of griefed pixels in the last read-back, in millionths
+    grief_ratio: AtomicU64,
+}
+
+/// Values of the counters at one point in time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Snapshot {
+    pub bytes: u64,
+    pub pixels: u64,
+    pub frames: u64,
+    pub correction_rounds: f64,
+    pub lag: Duration,
+    pub reconnects: u64,
+    pub connection_errors: u64,
+    pub sampled: u64,
+    pub griefed: u64,
+    /// None until pixels were read back
+    pub grief_ratio: Option<f64>,
+}
+
+impl Stats {
+    pub fn sent(&self, bytes: usize, pixels: u64) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.pixels.fetch_add(pixels, Ordering::Relaxed);
+    }
+
+    /// Records the end of a frame, `rounds` is how often each pixel of the corrections was redrawn
+    pub fn frame_played(&self, rounds: f64, lag: Duration) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.correction_rounds
+            .fetch_add((rounds * 1000.0) as u64, Ordering::Relaxed);
+        self.lag
+            .fetch_add(lag.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_failed(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_frame(&self, frame: usize) {
+        self.frame.store(frame as u64, Ordering::Relaxed);
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame.load(Ordering::Relaxed) as usize
+    }
+
+    pub fn read_back(&self, sampled: u64, griefed: u64) {
+        self.sampled.fetch_add(sampled, Ordering::Relaxed);
+        self.griefed.fetch_add(griefed, Ordering::Relaxed);
+        self.grief_ratio
+            .store(griefed * 1_000_000 / sampled.max(1), Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            pixels: self.pixels.load(Ordering::Relaxed),
+            frames: self.frames.load(Ordering::Relaxed),
+            correction_rounds: self.correction_rounds.load(Ordering::Relaxed) as f64 / 1000.0,
+            lag: Duration::from_millis(self.lag.load(Ordering::Relaxed)),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            connection_errors: self.connection_errors.load(Ordering::Relaxed),
+            sampled: self.sampled.load(Ordering::Relaxed),
+            griefed: self.griefed.load(Ordering::Relaxed),
+            grief_ratio: match self.sampled.load(Ordering::Relaxed) {
+                0 => None,
+                _ => Some(self.grief_ratio.load(Ordering::Relaxed) as f64 / 1_000_000.0),
+            },
+        }
+    }
+}
+
+/// Number of pixel commands in a block of instructions
+pub fn count_pixels(instructions: &[u8]) -> u64 {
+    instructions.iter().filter(|&&byte| byte == b'\n').count() as u64
+}
+
+/// Prints a status line per job with the rates since the previous one
+pub async fn report(jobs: Arc<Vec<JobHandle>>, interval: Duration) {
+    let mut previous: Vec<Snapshot> = jobs.iter().map(|job| job.stats.snapshot()).collect();
+    let mut last = Instant::now();
+    loop {
+        sleep(interval).await;
+        let seconds = last.elapsed().as_secs_f64();
+        last = Instant::now();
+        for (job, previous) in jobs.iter().zip(previous.iter_mut()) {
+            let now = job.stats.snapshot();
+            let frames = (now.frames - previous.frames).max(1) as f64;
+            info!(
+                "📊 {}: {}B/s, {} px/s, {:.1} frames/s, coverage {:.1}x, lag {:.0} ms, {} reconnects",
+                job.job().url,
+                si((now.bytes - previous.bytes) as f64 / seconds),
+                si((now.pixels - previous.pixels) as f64 / seconds),
+                (now.frames - previous.frames) as f64 / seconds,
+                (now.correction_rounds - previous.correction_rounds) / frames,
+                (now.lag - previous.lag).as_secs_f64() * 1000.0 / frames,
+                now.reconnects
+            );
+            *previous = now;
+        }
+    }
+}
+
+/// Formats a number with a metric prefix, e.g. 1.5M
+fn si(value: f64) -> String {
+    let (value, prefix) = match value {
+        v if v >= 1e9 => (v / 1e9, "G"),
+        v if v >= 1e6 => (v / 1e6, "M"),
+        v if v >= 1e3 => (v / 1e3, "k"),
+        v => (v, ""),
+    };
+    format!("{:.1}{}", value, prefix)
+}
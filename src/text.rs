@@ -0,0 +1,312 @@
+use ab_glyph::{point, Font, FontVec, Glyph, PxScale, ScaleFont};
+use std::error::Error;
+
+use crate::image_data::{Frame, Pixel};
+
+/// Options for rasterising a text source
+#[derive(Debug, Clone)]
+pub struct TextOptions {
+    pub text: String,
+    /// TrueType font file, the embedded bitmap font is used if none is given
+    pub font: Option<String>,
+    /// Line height of TrueType fonts in pixels
+    pub font_size: f32,
+    pub color: (u8, u8, u8),
+    /// Background colour, transparent if none is given
+    pub background: Option<(u8, u8, u8)>,
+    /// Integer factor by which the rendered text is scaled up
+    pub scale: u32,
+    /// Width of the window the text scrolls through, static text if none is given
+    pub marquee: Option<u32>,
+    /// Pixels the marquee text moves per frame
+    pub marquee_speed: u32,
+}
+
+impl Default for TextOptions {
+    fn default() -> Self {
+        TextOptions {
+            text: String::new(),
+            font: None,
+            font_size: 16.0,
+            color: (255, 255, 255),
+            background: None,
+            scale: 1,
+            marquee: None,
+            marquee_speed: 1,
+        }
+    }
+}
+
+/// Coverage of each pixel by the text, 0 is uncovered and 255 fully covered
+struct Mask {
+    coverage: Vec<u8>,
+    size: (u32, u32),
+}
+
+/// Rasterises the text into one frame, or one frame per step if it is a marquee
+pub fn render_text(options: &TextOptions) -> Result<Vec<Frame>, Box<dyn Error>> {
+    if options.text.is_empty() {
+        return Err("The text must not be empty".into());
+    }
+    let mask = match &options.font {
+        Some(path) => render_truetype(&options.text, path, options.font_size)?,
+        None => render_bitmap(&options.text),
+    }
+    .scale(options.scale.max(1));
+
+    match options.marquee {
+        None => Ok(vec![mask.to_frame(
+            0,
+            mask.size.0,
+            options.color,
+            options.background,
+            10,
+        )]),
+        Some(width) => {
+            // A moving text has to erase its old position, so it needs a background
+            let background = Some(options.background.unwrap_or((0, 0, 0)));
+            let speed = options.marquee_speed.max(1);
+            let distance = mask.size.0 + width;
+            Ok((0..distance.div_ceil(speed))
+                .map(|step| {
+                    let text_x = width as i64 - (step * speed) as i64;
+                    mask.to_frame(text_x, width, options.color, background, 1)
+                })
+                .collect())
+        }
+    }
+}
+
+impl Mask {
+    fn scale(self, factor: u32) -> Self {
+        if factor == 1 {
+            return self;
+        }
+        let size = (self.size.0 * factor, self.size.1 * factor);
+        let coverage = (0..size.0 * size.1)
+            .map(|i| {
+                let x = (i % size.0) / factor;
+                let y = (i / size.0) / factor;
+                self.coverage[(x + self.size.0 * y) as usize]
+            })
+            .collect();
+        Mask { coverage, size }
+    }
+
+    /// Colours a window of the given width with the text starting at `text_x`
+    fn to_frame(
+        &self,
+        text_x: i64,
+        width: u32,
+        color: (u8, u8, u8),
+        background: Option<(u8, u8, u8)>,
+        delay: u16,
+    ) -> Frame {
+        let image = (0..width * self.size.1)
+            .map(|i| {
+                let x = (i % width) as i64 - text_x;
+                let y = i / width;
+                let coverage = if x >= 0 && x < self.size.0 as i64 {
+                    self.coverage[(x as u32 + self.size.0 * y) as usize]
+                } else {
+                    0
+                };
+                match background {
+                    Some(bg) => {
+                        let blend = |fg: u8, bg: u8| {
+                            ((fg as u32 * coverage as u32 + bg as u32 * (255 - coverage as u32))
+                                / 255) as u8
+                        };
+                        Pixel::Rgb(
+                            blend(color.0, bg.0),
+                            blend(color.1, bg.1),
+                            blend(color.2, bg.2),
+                        )
+                    }
+                    None if coverage >= 128 => Pixel::Rgb(color.0, color.1, color.2),
+                    None => Pixel::Empty,
+                }
+            })
+            .collect();
+        Frame::new(image, (0, 0), (width, self.size.1), delay)
+    }
+}
+
+fn render_bitmap(text: &str) -> Mask {
+    let lines: Vec<Vec<char>> = text.lines().map(|line| line.chars().collect()).collect();
+    let columns = lines.iter().map(Vec::len).max().unwrap_or(0) as u32;
+    let size = (
+        (columns * GLYPH_WIDTH).saturating_sub(1),
+        (lines.len() as u32 * GLYPH_HEIGHT).saturating_sub(1),
+    );
+    let mut coverage = vec![0; (size.0 * size.1) as usize];
+    for (row, line) in lines.iter().enumerate() {
+        for (column, &c) in line.iter().enumerate() {
+            let glyph = match c {
+                ' '..='~' => &FONT_5X7[c as usize - ' ' as usize],
+                _ => &FONT_5X7['?' as usize - ' ' as usize],
+            };
+            let x0 = column as u32 * GLYPH_WIDTH;
+            let y0 = row as u32 * GLYPH_HEIGHT;
+            for (x, bits) in glyph.iter().enumerate() {
+                for y in 0..7 {
+                    if bits >> y & 1 == 1 {
+                        coverage[(x0 + x as u32 + size.0 * (y0 + y)) as usize] = 255;
+                    }
+                }
+            }
+        }
+    }
+    Mask { coverage, size }
+}
+
+fn render_truetype(text: &str, path: &str, font_size: f32) -> Result<Mask, Box<dyn Error>> {
+    let font = FontVec::try_from_vec(std::fs::read(path)?)?;
+    let font = font.as_scaled(PxScale::from(font_size));
+    let line_height = font.height() + font.line_gap();
+
+    let mut glyphs: Vec<Glyph> = Vec::new();
+    for (row, line) in text.lines().enumerate() {
+        let mut caret = point(0.0, font.ascent() + row as f32 * line_height);
+        let mut previous = None;
+        for c in line.chars() {
+            let mut glyph = font.scaled_glyph(c);
+            if let Some(previous) = previous {
+                caret.x += font.kern(previous, glyph.id);
+            }
+            glyph.position = caret;
+            caret.x += font.h_advance(glyph.id);
+            previous = Some(glyph.id);
+            glyphs.push(glyph);
+        }
+    }
+
+    let outlines: Vec<_> = glyphs
+        .into_iter()
+        .filter_map(|glyph| font.outline_glyph(glyph))
+        .collect();
+    let size = outlines.iter().fold((0, 0), |(w, h), outline| {
+        let bounds = outline.px_bounds();
+        (
+            w.max(bounds.max.x.max(0.0).ceil() as u32),
+            h.max(bounds.max.y.max(0.0).ceil() as u32),
+        )
+    });
+    let mut coverage = vec![0; (size.0 * size.1) as usize];
+    for outline in outlines {
+        let bounds = outline.px_bounds();
+        outline.draw(|x, y, c| {
+            let x = bounds.min.x as i32 + x as i32;
+            let y = bounds.min.y as i32 + y as i32;
+            if x >= 0 && y >= 0 {
+                let idx = (x as u32 + size.0 * y as u32) as usize;
+                coverage[idx] = coverage[idx].max((c.min(1.0) * 255.0) as u8);
+            }
+        });
+    }
+    Ok(Mask { coverage, size })
+}
+
+/// Width of a glyph of the bitmap font including spacing
+const GLYPH_WIDTH: u32 = 6;
+/// Height of a glyph of the bitmap font including spacing
+const GLYPH_HEIGHT: u32 = 8;
+
+/// Printable ASCII characters as columns with the top row in the lowest bit
+const FONT_5X7: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // '!'
+    [0x00, 0x07, 0x00, 0x07, 0x00], // '"'
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // '#'
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // '$'
+    [0x23, 0x13, 0x08, 0x64, 0x62], // '%'
+    [0x36, 0x49, 0x56, 0x20, 0x50], // '&'
+    [0x00, 0x00, 0x07, 0x00, 0x00], // '''
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // '('
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // ')'
+    [0x14, 0x08, 0x3e, 0x08, 0x14], // '*'
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // '+'
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ','
+    [0x08, 0x08, 0x08, 0x08, 0x08], // '-'
+    [0x00, 0x60, 0x60, 0x00, 0x00], // '.'
+    [0x20, 0x10, 0x08, 0x04, 0x02], // '/'
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // '0'
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // '1'
+    [0x42, 0x61, 0x51, 0x49, 0x46], // '2'
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // '3'
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // '4'
+    [0x27, 0x45, 0x45, 0x45, 0x39], // '5'
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // '6'
+    [0x01, 0x71, 0x09, 0x05, 0x03], // '7'
+    [0x36, 0x49, 0x49, 0x49, 0x36], // '8'
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // '9'
+    [0x00, 0x36, 0x36, 0x00, 0x00], // ':'
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ';'
+    [0x08, 0x14, 0x22, 0x41, 0x00], // '<'
+    [0x14, 0x14, 0x14, 0x14, 0x14], // '='
+    [0x00, 0x41, 0x22, 0x14, 0x08], // '>'
+    [0x02, 0x01, 0x51, 0x09, 0x06], // '?'
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // '@'
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // 'A'
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // 'B'
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // 'C'
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // 'D'
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // 'E'
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // 'F'
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // 'G'
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // 'H'
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // 'I'
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // 'J'
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // 'K'
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // 'L'
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // 'M'
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // 'N'
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // 'O'
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // 'P'
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // 'Q'
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // 'R'
+    [0x46, 0x49, 0x49, 0x49, 0x31], // 'S'
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // 'T'
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // 'U'
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // 'V'
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // 'W'
+    [0x63, 0x14, 0x08, 0x14, 0x63], // 'X'
+    [0x07, 0x08, 0x70, 0x08, 0x07], // 'Y'
+    [0x61, 0x51, 0x49, 0x45, 0x43], // 'Z'
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // '['
+    [0x02, 0x04, 0x08, 0x10, 0x20], // '\'
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ']'
+    [0x04, 0x02, 0x01, 0x02, 0x04], // '^'
+    [0x40, 0x40, 0x40, 0x40, 0x40], // '_'
+    [0x00, 0x01, 0x02, 0x04, 0x00], // '`'
+    [0x20, 0x54, 0x54, 0x54, 0x78], // 'a'
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // 'b'
+    [0x38, 0x44, 0x44, 0x44, 0x20], // 'c'
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // 'd'
+    [0x38, 0x54, 0x54, 0x54, 0x18], // 'e'
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // 'f'
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // 'g'
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // 'h'
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // 'i'
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // 'j'
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // 'k'
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // 'l'
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // 'm'
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // 'n'
+    [0x38, 0x44, 0x44, 0x44, 0x38], // 'o'
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // 'p'
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // 'q'
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // 'r'
+    [0x48, 0x54, 0x54, 0x54, 0x20], // 's'
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // 't'
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // 'u'
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // 'v'
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // 'w'
+    [0x44, 0x28, 0x10, 0x28, 0x44], // 'x'
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // 'y'
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // 'z'
+    [0x00, 0x08, 0x36, 0x41, 0x00], // '{'
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // '|'
+    [0x00, 0x41, 0x36, 0x08, 0x00], // '}'
+    [0x08, 0x04, 0x08, 0x10, 0x08], // '~'
+];
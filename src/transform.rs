@@ -0,0 +1,357 @@
+use rayon::prelude::*;
+use std::str::FromStr;
+
+use crate::compose::Timeline;
+use crate::image_data::{Frame, Pixel};
+
+/// Geometric change applied to all frames of an animation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    /// Keeps the rectangle with the given offset and size
+    Crop {
+        offset: (u32, u32),
+        size: (u32, u32),
+    },
+    /// Rotates clockwise by 90, 180 or 270 degrees
+    Rotate(u32),
+    FlipHorizontal,
+    FlipVertical,
+    /// Integer nearest-neighbour upscaling
+    Scale(u32),
+    /// Doubles the size while smoothing diagonal edges of pixel art
+    Scale2x,
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    /// Parses `crop:X,Y,WxH`, `rotate:90|180|270`, `flip:h|v`, `scale:N` or `scale2x`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = s.split_once(':').unwrap_or((s, ""));
+        let transform = match (name, arg) {
+            ("crop", rect) => (|| {
+                let mut parts = rect.splitn(3, ',');
+                let x = parts.next()?.parse().ok()?;
+                let y = parts.next()?.parse().ok()?;
+                let (width, height) = parts.next()?.split_once('x')?;
+                Some(Transform::Crop {
+                    offset: (x, y),
+                    size: (width.parse().ok()?, height.parse().ok()?),
+                })
+            })(),
+            ("rotate", degrees) => match degrees {
+                "90" | "180" | "270" => Some(Transform::Rotate(degrees.parse().unwrap())),
+                _ => None,
+            },
+            ("flip", "h") => Some(Transform::FlipHorizontal),
+            ("flip", "v") => Some(Transform::FlipVertical),
+            ("scale", factor) => factor
+                .parse()
+                .ok()
+                .filter(|&factor| factor > 0)
+                .map(Transform::Scale),
+            ("scale2x", "") => Some(Transform::Scale2x),
+            _ => None,
+        };
+        transform.ok_or_else(|| {
+            format!(
+                "Invalid transform '{}', expected crop:X,Y,WxH, rotate:90|180|270, flip:h|v, scale:N or scale2x",
+                s
+            )
+        })
+    }
+}
+
+/// Applies the transforms in order
+///
+/// Frames that only cover a part of the animation keep covering the same
+/// part after flipping, rotating and cropping.
+pub fn apply(mut frames: Vec<Frame>, transforms: &[Transform]) -> Vec<Frame> {
+    for transform in transforms {
+        let canvas = frames.iter().fold((0, 0), |size, frame| {
+            let end = frame.end();
+            (size.0.max(end.0), size.1.max(end.1))
+        });
+        frames = match *transform {
+            Transform::Crop { offset, size } => frames
+                .iter()
+                .map(|frame| crop(frame, offset, size))
+                .collect(),
+            Transform::Rotate(degrees) => frames
+                .iter()
+                .map(|frame| rotate(frame, degrees, canvas))
+                .collect(),
+            Transform::FlipHorizontal => frames
+                .iter()
+                .map(|frame| {
+                    let (x, y) = frame.offset();
+                    let (width, _) = frame.size();
+                    let offset = (canvas.0 - x - width, y);
+                    remap(frame, offset, frame.size(), |nx, ny| (width - 1 - nx, ny))
+                })
+                .collect(),
+            Transform::FlipVertical => frames
+                .iter()
+                .map(|frame| {
+                    let (x, y) = frame.offset();
+                    let (_, height) = frame.size();
+                    let offset = (x, canvas.1 - y - height);
+                    remap(frame, offset, frame.size(), |nx, ny| (nx, height - 1 - ny))
+                })
+                .collect(),
+            Transform::Scale(factor) => frames.iter().map(|frame| frame.scale(factor)).collect(),
+            // Neighbours outside of partial frames are unknown, so work on complete images
+            Transform::Scale2x => Timeline::new(frames, (0, 0))
+                .states()
+                .iter()
+                .map(scale2x)
+                .collect(),
+        };
+    }
+    frames
+}
+
+/// Builds a frame by sampling each new pixel from a position relative to the old frame
+fn remap<F>(frame: &Frame, offset: (u32, u32), size: (u32, u32), source: F) -> Frame
+where
+    F: Fn(u32, u32) -> (u32, u32) + Sync,
+{
+    let (ox, oy) = frame.offset();
+    let image = (0..size.0 * size.1)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = source(i % size.0, i / size.0);
+            frame.get(ox + x, oy + y)
+        })
+        .collect();
+    Frame::new(image, offset, size, frame.delay())
+}
+
+fn crop(frame: &Frame, offset: (u32, u32), size: (u32, u32)) -> Frame {
+    let (x0, y0) = (
+        frame.offset().0.max(offset.0),
+        frame.offset().1.max(offset.1),
+    );
+    let (x1, y1) = (
+        frame.end().0.min(offset.0 + size.0),
+        frame.end().1.min(offset.1 + size.1),
+    );
+    if x1 <= x0 || y1 <= y0 {
+        // Keep the frame for its delay
+        return Frame::new(Vec::new(), (0, 0), (0, 0), frame.delay());
+    }
+    let (dx, dy) = (x0 - frame.offset().0, y0 - frame.offset().1);
+    remap(
+        frame,
+        (x0 - offset.0, y0 - offset.1),
+        (x1 - x0, y1 - y0),
+        |nx, ny| (nx + dx, ny + dy),
+    )
+}
+
+fn rotate(frame: &Frame, degrees: u32, canvas: (u32, u32)) -> Frame {
+    let (x, y) = frame.offset();
+    let (width, height) = frame.size();
+    match degrees {
+        90 => remap(
+            frame,
+            (canvas.1 - y - height, x),
+            (height, width),
+            |nx, ny| (ny, height - 1 - nx),
+        ),
+        180 => remap(
+            frame,
+            (canvas.0 - x - width, canvas.1 - y - height),
+            (width, height),
+            |nx, ny| (width - 1 - nx, height - 1 - ny),
+        ),
+        _ => remap(
+            frame,
+            (y, canvas.0 - x - width),
+            (height, width),
+            |nx, ny| (width - 1 - ny, nx),
+        ),
+    }
+}
+
+/// Scales a complete image by two with the EPX/Scale2x algorithm
+fn scale2x(frame: &Frame) -> Frame {
+    let (ox, oy) = frame.offset();
+    let (width, height) = frame.size();
+    let pixel = |x: u32, y: u32| frame.get(ox + x, oy + y);
+    let image = (0..width * height * 4)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % (width * 2) / 2, i / (width * 2) / 2);
+            let (right, bottom) = (i % 2 == 1, i / (width * 2) % 2 == 1);
+            let p = pixel(x, y);
+            let a = pixel(x, y.saturating_sub(1));
+            let b = pixel((x + 1).min(width - 1), y);
+            let c = pixel(x.saturating_sub(1), y);
+            let d = pixel(x, (y + 1).min(height - 1));
+            // Corner pixel takes the colour of its two neighbours if they match
+            let (h, v, h_other, v_other) = match (right, bottom) {
+                (false, false) => (c, a, b, d),
+                (true, false) => (b, a, c, d),
+                (false, true) => (c, d, b, a),
+                (true, true) => (b, d, c, a),
+            };
+            if h == v && h != v_other && v != h_other {
+                v
+            } else {
+                p
+            }
+        })
+        .collect::<Vec<Pixel>>();
+    Frame::new(
+        image,
+        (ox * 2, oy * 2),
+        (width * 2, height * 2),
+        frame.delay(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_transform() {
+        assert_eq!(
+            "crop:10,20,300x200".parse(),
+            Ok(Transform::Crop {
+                offset: (10, 20),
+                size: (300, 200),
+            })
+        );
+        assert_eq!("rotate:90".parse(), Ok(Transform::Rotate(90)));
+        assert_eq!("rotate:180".parse(), Ok(Transform::Rotate(180)));
+        assert_eq!("rotate:270".parse(), Ok(Transform::Rotate(270)));
+        assert_eq!("flip:h".parse(), Ok(Transform::FlipHorizontal));
+        assert_eq!("flip:v".parse(), Ok(Transform::FlipVertical));
+        assert_eq!("scale:3".parse(), Ok(Transform::Scale(3)));
+        assert_eq!("scale2x".parse(), Ok(Transform::Scale2x));
+    }
+
+    #[test]
+    fn parse_invalid_transform() {
+        for transform in &[
+            "",
+            "mirror",
+            "crop:10,20",
+            "crop:10,20,300x",
+            "crop:-10,20,300x200",
+            "crop:10,20,300x200,1",
+            "rotate:0",
+            "rotate:45",
+            "flip:hv",
+            "scale:0",
+            "scale:1.5",
+            "scale2x:2",
+        ] {
+            assert!(transform.parse::<Transform>().is_err(), "{}", transform);
+        }
+    }
+
+    /// Frame with a pixel of a distinct colour for every letter, `.` is empty
+    fn frame(offset: (u32, u32), rows: &[&str]) -> Frame {
+        let image = rows
+            .iter()
+            .flat_map(|row| row.bytes())
+            .map(|byte| match byte {
+                b'.' => Pixel::Empty,
+                byte => Pixel::Rgb(byte, 0, 0),
+            })
+            .collect();
+        let size = (rows[0].len() as u32, rows.len() as u32);
+        Frame::new(image, offset, size, 10)
+    }
+
+    fn rows(frame: &Frame) -> ((u32, u32), Vec<String>) {
+        let (ox, oy) = frame.offset();
+        let (width, height) = frame.size();
+        let rows = (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| match frame.get(ox + x, oy + y) {
+                        Pixel::Rgb(byte, _, _) => byte as char,
+                        _ => '.',
+                    })
+                    .collect()
+            })
+            .collect();
+        (frame.offset(), rows)
+    }
+
+    fn transformed(transform: Transform) -> Vec<((u32, u32), Vec<String>)> {
+        // The second frame only changes the top right pixel
+        let frames = vec![frame((0, 0), &["abc", "def"]), frame((2, 0), &["x"])];
+        apply(frames, &[transform]).iter().map(rows).collect()
+    }
+
+    fn expect(offset: (u32, u32), rows: &[&str]) -> ((u32, u32), Vec<String>) {
+        (offset, rows.iter().map(|row| row.to_string()).collect())
+    }
+
+    #[test]
+    fn rotate_partial_frames() {
+        assert_eq!(
+            transformed(Transform::Rotate(90)),
+            [expect((0, 0), &["da", "eb", "fc"]), expect((1, 2), &["x"])]
+        );
+        assert_eq!(
+            transformed(Transform::Rotate(180)),
+            [expect((0, 0), &["fed", "cba"]), expect((0, 1), &["x"])]
+        );
+        assert_eq!(
+            transformed(Transform::Rotate(270)),
+            [expect((0, 0), &["cf", "be", "ad"]), expect((0, 0), &["x"])]
+        );
+    }
+
+    #[test]
+    fn flip_partial_frames() {
+        assert_eq!(
+            transformed(Transform::FlipHorizontal),
+            [expect((0, 0), &["cba", "fed"]), expect((0, 0), &["x"])]
+        );
+        assert_eq!(
+            transformed(Transform::FlipVertical),
+            [expect((0, 0), &["def", "abc"]), expect((2, 1), &["x"])]
+        );
+    }
+
+    #[test]
+    fn crop_partial_frames() {
+        let crop = |offset, size| Transform::Crop { offset, size };
+        assert_eq!(
+            transformed(crop((1, 0), (2, 2))),
+            [expect((0, 0), &["bc", "ef"]), expect((1, 0), &["x"])]
+        );
+        // Frames outside of the rectangle are kept empty for their delay
+        let frames = apply(
+            vec![frame((0, 0), &["abc", "def"]), frame((2, 0), &["x"])],
+            &[crop((0, 1), (2, 1))],
+        );
+        assert_eq!(rows(&frames[0]), expect((0, 0), &["de"]));
+        assert_eq!(frames[1].size(), (0, 0));
+        assert_eq!(frames[1].delay(), 10);
+    }
+
+    #[test]
+    fn scale2x_smooths_diagonals() {
+        let frames = vec![frame((0, 0), &["bb", "bb"]), frame((0, 0), &["r"])];
+        let frames: Vec<_> = apply(frames, &[Transform::Scale2x])
+            .iter()
+            .map(rows)
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                expect((0, 0), &["bbbb", "bbbb", "bbbb", "bbbb"]),
+                // Partial frames are scaled as the complete image they show
+                expect((0, 0), &["rrbb", "rbbb", "bbbb", "bbbb"]),
+            ]
+        );
+    }
+}
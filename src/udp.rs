@@ -0,0 +1,313 @@
+use futures::ready;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    time::{sleep, Instant, Sleep},
+};
+
+use crate::limit::Bucket;
+
+/// Datagram socket that commands are written to like a byte stream, they are
+/// packed into datagrams without splitting any of them
+pub struct Udp {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    packet_size: usize,
+    /// Whether pixels are sent as binary `PB` commands
+    binary: bool,
+    /// Limits the datagrams per second
+    pace: Option<Bucket>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    /// Start of a command that was not completely written yet
+    line: Vec<u8>,
+    /// Datagram that is being filled
+    packet: Vec<u8>,
+    /// Full datagram waiting to be sent
+    ready: Option<Vec<u8>>,
+}
+
+impl Udp {
+    pub fn new(
+        socket: UdpSocket,
+        peer: SocketAddr,
+        packet_size: usize,
+        binary: bool,
+        packet_rate: Option<u64>,
+    ) -> Self {
+        Udp {
+            socket,
+            peer,
+            packet_size,
+            binary,
+            pace: packet_rate.map(Bucket::new),
+            sleep: None,
+            line: Vec::new(),
+            packet: Vec::with_capacity(packet_size),
+            ready: None,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Adds the complete command in `line` to the datagram, the datagram is
+    /// ready to be sent first if the command doesn't fit into it anymore
+    fn push_line(&mut self) {
+        let binary = match self.binary {
+            true => encode_binary(&self.line),
+            false => None,
+        };
+        let command = binary.as_ref().map_or(&self.line[..], |binary| &binary[..]);
+        if !self.packet.is_empty() && self.packet.len() + command.len() > self.packet_size {
+            self.ready = Some(std::mem::replace(
+                &mut self.packet,
+                Vec::with_capacity(self.packet_size),
+            ));
+        }
+        self.packet.extend_from_slice(command);
+        self.line.clear();
+    }
+
+    /// Sends the datagram that is ready, once the pace allows it
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let packet = match &self.ready {
+            Some(packet) => packet,
+            None => return Poll::Ready(Ok(())),
+        };
+        if let Some(pace) = &mut self.pace {
+            pace.refill(std::time::Instant::now());
+            let wait = pace.wait(1.0);
+            if !wait.is_zero() {
+                let timer = self.sleep.get_or_insert_with(|| Box::pin(sleep(wait)));
+                timer.as_mut().reset(Instant::now() + wait);
+                ready!(timer.as_mut().poll(cx));
+            }
+        }
+        ready!(self.socket.poll_send(cx, packet))?;
+        if let Some(pace) = &mut self.pace {
+            pace.take(1.0);
+        }
+        self.ready = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Udp {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.socket.poll_recv(cx, buf)
+    }
+}
+
+impl AsyncWrite for Udp {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut written = 0;
+        while written < buf.len() {
+            match this.poll_send(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending if written > 0 => break,
+                Poll::Pending => return Poll::Pending,
+            }
+            let rest = &buf[written..];
+            match rest.iter().position(|&byte| byte == b'\n') {
+                Some(end) => {
+                    this.line.extend_from_slice(&rest[..=end]);
+                    written += end + 1;
+                    this.push_line();
+                }
+                None => {
+                    this.line.extend_from_slice(rest);
+                    written = buf.len();
+                }
+            }
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    /// Sends the datagram even if it isn't full
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_send(cx))?;
+            if this.packet.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.ready = Some(std::mem::take(&mut this.packet));
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Encodes `PX <X> <Y> <RRGGBB[AA]>` as `PB`, the coordinates as 16 bit little
+/// endian followed by the colour with alpha, other commands stay text
+fn encode_binary(line: &[u8]) -> Option<[u8; 10]> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.split_whitespace();
+    if parts.next()? != "PX" {
+        return None;
+    }
+    let x: u16 = parts.next()?.parse().ok()?;
+    let y: u16 = parts.next()?.parse().ok()?;
+    let hex = parts.next()?;
+    let color = match hex.len() {
+        6 => u32::from_str_radix(hex, 16).ok()? << 8 | 0xff,
+        8 => u32::from_str_radix(hex, 16).ok()?,
+        _ => return None,
+    };
+    let mut command = [0; 10];
+    command[..2].copy_from_slice(b"PB");
+    command[2..4].copy_from_slice(&x.to_le_bytes());
+    command[4..6].copy_from_slice(&y.to_le_bytes());
+    command[6..].copy_from_slice(&color.to_be_bytes());
+    Some(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{self, NetOptions, Stream};
+    use std::time::Duration;
+    use tokio::{io::AsyncWriteExt, runtime::Runtime};
+
+    /// Writes the commands in chunks of the given size, which split lines
+    /// anywhere, and returns the datagrams the server received
+    fn send(commands: &[u8], chunk: usize, options: NetOptions) -> Vec<Vec<u8>> {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let url = format!("udp://{}", server.local_addr().unwrap());
+        Runtime::new().unwrap().block_on(async {
+            let mut stream = net::connect_with(&url, &options).await.unwrap();
+            for chunk in commands.chunks(chunk) {
+                stream.write_all(chunk).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+        });
+        let mut datagrams = Vec::new();
+        let mut buf = [0; 2048];
+        while let Ok(n) = server.recv(&mut buf) {
+            datagrams.push(buf[..n].to_vec());
+        }
+        datagrams
+    }
+
+    fn packet_size(url: &str) -> usize {
+        Runtime::new().unwrap().block_on(async {
+            match net::connect_with(url, &NetOptions::default())
+                .await
+                .unwrap()
+            {
+                Stream::Udp(udp) => udp.packet_size,
+                _ => panic!("{} is no UDP stream", url),
+            }
+        })
+    }
+
+    #[test]
+    fn commands_are_never_split() {
+        let commands: Vec<u8> = (0..100)
+            .flat_map(|i| format!("PX {} {} ff{:04x}\n", i, i * 7, i).into_bytes())
+            .collect();
+        let options = NetOptions {
+            udp_packet_size: Some(64),
+            ..NetOptions::default()
+        };
+        for &chunk in &[1, 7, 100, commands.len()] {
+            let datagrams = send(&commands, chunk, options.clone());
+            for datagram in &datagrams {
+                assert!(datagram.len() <= 64, "{:?}", datagram);
+                assert!(datagram.ends_with(b"\n"), "{:?}", datagram);
+            }
+            // Only the datagram sent by the flush has room for another command
+            for datagram in &datagrams[..datagrams.len() - 1] {
+                assert!(datagram.len() > 64 - 20, "{:?}", datagram);
+            }
+            assert_eq!(datagrams.concat(), commands);
+        }
+    }
+
+    #[test]
+    fn long_commands_get_their_own_datagram() {
+        let long = format!("PX 1 2 {}\n", "f".repeat(40));
+        let commands = format!("SIZE\n{}PX 3 4 00ff00\n", long);
+        let options = NetOptions {
+            udp_packet_size: Some(16),
+            ..NetOptions::default()
+        };
+        let datagrams = send(commands.as_bytes(), 3, options);
+        let expected: Vec<&[u8]> = vec![b"SIZE\n", long.as_bytes(), b"PX 3 4 00ff00\n"];
+        assert_eq!(datagrams, expected);
+    }
+
+    #[test]
+    fn default_packet_sizes() {
+        assert_eq!(packet_size("udp://127.0.0.1:1337"), 1472);
+        assert_eq!(packet_size("udp://[::1]:1337"), 1452);
+    }
+
+    #[test]
+    fn binary_pixels() {
+        let options = NetOptions {
+            udp_binary: true,
+            ..NetOptions::default()
+        };
+        let datagrams = send(b"SIZE\nPX 258 3 ff8000\nPX 1 2 11223344\n", 5, options);
+        let mut expected = b"SIZE\n".to_vec();
+        expected.extend_from_slice(&[b'P', b'B', 2, 1, 3, 0, 0xff, 0x80, 0x00, 0xff]);
+        expected.extend_from_slice(&[b'P', b'B', 1, 0, 2, 0, 0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(datagrams, vec![expected]);
+    }
+
+    #[test]
+    fn encode_pixels() {
+        assert_eq!(
+            encode_binary(b"PX 258 3 ff8000\n"),
+            Some([b'P', b'B', 2, 1, 3, 0, 0xff, 0x80, 0x00, 0xff])
+        );
+        assert_eq!(
+            encode_binary(b"PX 65535 0 abcdef80\n"),
+            Some([b'P', b'B', 0xff, 0xff, 0, 0, 0xab, 0xcd, 0xef, 0x80])
+        );
+    }
+
+    #[test]
+    fn other_commands_stay_text() {
+        for line in &[
+            &b"SIZE\n"[..],
+            b"HELP\n",
+            b"PX 1 2\n",
+            b"PX 65536 0 ff0000\n",
+            b"PX -1 0 ff0000\n",
+            b"PX 1 2 fff\n",
+            b"PX 1 2 gg0000\n",
+            b"OFFSET 10 10\n",
+        ] {
+            assert_eq!(encode_binary(line), None, "{:?}", line);
+        }
+    }
+}
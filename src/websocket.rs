@@ -0,0 +1,191 @@
+use futures::{ready, Sink, Stream as _};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{self, Message},
+    WebSocketStream,
+};
+
+use crate::net::Stream;
+
+/// Largest message the commands are batched into
+const MESSAGE_SIZE: usize = 64 * 1024;
+
+/// WebSocket connection that is written to and read from like a byte stream,
+/// commands are sent in messages of many whole lines
+pub struct WebSocket {
+    inner: WebSocketStream<Stream>,
+    /// Commands that were not sent yet
+    outgoing: Vec<u8>,
+    /// Payload of the last message received and how much of it was read
+    incoming: Vec<u8>,
+    read: usize,
+}
+
+impl WebSocket {
+    /// Performs the WebSocket handshake over a connection to the server
+    pub async fn connect(url: &str, stream: Stream) -> io::Result<Self> {
+        let (inner, _) = tokio_tungstenite::client_async(url, stream)
+            .await
+            .map_err(error)?;
+        Ok(WebSocket {
+            inner,
+            outgoing: Vec::with_capacity(MESSAGE_SIZE),
+            incoming: Vec::new(),
+            read: 0,
+        })
+    }
+
+    pub fn get_ref(&self) -> &Stream {
+        self.inner.get_ref()
+    }
+
+    /// Sends the complete lines in the buffer as one message, or all of it if
+    /// it holds no complete line
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.outgoing.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(error)?;
+        let end = self
+            .outgoing
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(self.outgoing.len(), |i| i + 1);
+        let rest = self.outgoing.split_off(end);
+        let payload = std::mem::replace(&mut self.outgoing, rest);
+        let message = match String::from_utf8(payload) {
+            Ok(text) => Message::Text(text),
+            Err(err) => Message::Binary(err.into_bytes()),
+        };
+        Pin::new(&mut self.inner)
+            .start_send(message)
+            .map_err(error)?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for WebSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read == this.incoming.len() {
+            this.incoming = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                Some(Ok(Message::Binary(data))) => data,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // Pings are answered by tungstenite
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Poll::Ready(Err(error(err))),
+            };
+            // Replies are read line by line, messages may leave out the last newline
+            if !this.incoming.is_empty() && !this.incoming.ends_with(b"\n") {
+                this.incoming.push(b'\n');
+            }
+            this.read = 0;
+        }
+        let n = buf.remaining().min(this.incoming.len() - this.read);
+        buf.put_slice(&this.incoming[this.read..this.read + n]);
+        this.read += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WebSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.outgoing.len() >= MESSAGE_SIZE {
+            ready!(this.poll_send(cx))?;
+        }
+        let n = buf.len().min(MESSAGE_SIZE - this.outgoing.len());
+        this.outgoing.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx).map_err(error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx).map_err(error)
+    }
+}
+
+fn error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::other(format!("WebSocket: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        runtime::Runtime,
+    };
+
+    use crate::net;
+
+    #[test]
+    fn messages_hold_whole_lines() {
+        Runtime::new().unwrap().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/", listener.local_addr().unwrap());
+            let server = tokio::spawn(async move {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let mut socket = tokio_tungstenite::accept_async(tcp).await.unwrap();
+                let mut messages = Vec::new();
+                while let Some(Ok(Message::Text(text))) = socket.next().await {
+                    if text == "SIZE\n" {
+                        // Without a trailing newline like some servers send it
+                        let reply = Message::Text("SIZE 800 600".into());
+                        socket.send(reply).await.unwrap();
+                    } else {
+                        messages.push(text);
+                    }
+                }
+                messages
+            });
+
+            let commands: String = (0..10_000)
+                .map(|i| format!("PX {} {} ff8000\n", i % 800, i / 800))
+                .collect();
+            let mut stream = BufReader::new(net::connect(&url).await.unwrap());
+            stream.write_all(commands.as_bytes()).await.unwrap();
+            stream.flush().await.unwrap();
+            stream.write_all(b"SIZE\n").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "SIZE 800 600\n");
+            stream.shutdown().await.unwrap();
+
+            let messages = server.await.unwrap();
+            assert!(messages.len() > 1);
+            for message in &messages {
+                assert!(message.len() <= MESSAGE_SIZE);
+                assert!(message.ends_with('\n'));
+            }
+            assert_eq!(messages.concat(), commands);
+        });
+    }
+}